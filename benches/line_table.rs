@@ -0,0 +1,64 @@
+// Measures how much memory the run-length encoded line and span tables use
+// compared to storing a line and a span for every byte of bytecode. Run with
+// `cargo bench --bench line_table`.
+
+use std::mem::size_of;
+use std::time::Instant;
+
+use clox::chunk::{LineRun, SpanRun};
+use clox::token::Span;
+use clox::{Compiler, Vm};
+
+// A script in the style of our generated config files: lots of globals, some
+// arithmetic, and the occasional loop
+fn large_script(statements: usize) -> String {
+    let mut source = String::new();
+    for i in 0..statements {
+        match i % 4 {
+            0 => source.push_str(&format!("var setting{i} = {i};\n")),
+            1 => source.push_str(&format!("var name{i} = \"option \" + \"{i}\";\n")),
+            2 => source.push_str(&format!("setting{} = setting{} * 2 + 1;\n", i - 2, i - 2)),
+            _ => source.push_str(&format!(
+                "for (var j = 0; j < 3; j = j + 1) {{ setting{} = setting{} + j; }}\n",
+                i - 3,
+                i - 3
+            )),
+        }
+    }
+    source
+}
+
+fn main() {
+    for statements in [1_000, 10_000, 50_000] {
+        let source = large_script(statements);
+        let mut vm = Vm::new();
+        let function = Compiler::compile(&mut vm, &source).expect("script should compile");
+        let chunk = &function.chunk;
+
+        let code = chunk.code.len();
+        let lines_per_byte = code * size_of::<usize>();
+        let spans_per_byte = code * size_of::<Span>();
+        let line_runs = chunk.lines.len() * size_of::<LineRun>();
+        let span_runs = chunk.spans.len() * size_of::<SpanRun>();
+
+        let start = Instant::now();
+        let mut checksum = 0;
+        for offset in 0..code {
+            checksum += chunk.line_at(offset);
+        }
+        let lookup = start.elapsed() / code as u32;
+
+        println!("{statements} statements ({code} bytes of bytecode, line checksum {checksum}):");
+        println!(
+            "  lines: {lines_per_byte:>9} bytes with one per byte, {line_runs:>8} bytes as {} runs ({:.1}x smaller)",
+            chunk.lines.len(),
+            lines_per_byte as f64 / line_runs as f64
+        );
+        println!(
+            "  spans: {spans_per_byte:>9} bytes with one per byte, {span_runs:>8} bytes as {} runs ({:.1}x smaller)",
+            chunk.spans.len(),
+            spans_per_byte as f64 / span_runs as f64
+        );
+        println!("  line_at: {lookup:?} per lookup");
+    }
+}
@@ -0,0 +1,761 @@
+use fnv::FnvHashMap;
+
+use crate::chunk::{OpCode, CAPTURE_LOCAL, CAPTURE_WIDE};
+use crate::error::AssembleError;
+use crate::function::Function;
+use crate::gc::{Gc, Root};
+use crate::object::Object;
+use crate::token::Span;
+use crate::value::Value;
+use crate::vm::Vm;
+
+// Turns a listing in the format printed by the disassembler back into
+// bytecode, e.g.
+//
+//   == <fn add> (arity 2) ==
+//   0000    1 OP_GET_LOCAL        1
+//   0002    | OP_GET_LOCAL        2
+//   0004    | OP_ADD
+//   0005    | OP_RETURN
+//
+// The offsets and line numbers on the left are optional, as are constant
+// indexes, so the same code can be written by hand as
+//
+//   OP_GET_LOCAL 1
+//   OP_GET_LOCAL 2
+//   OP_ADD
+//   OP_RETURN
+//
+// Jumps can go to a label (a name followed by `:` on its own line) instead of
+// an offset, e.g. `OP_JUMP -> done`, and `;` starts a comment.
+//
+// A listing can have several functions, each starting with a `==` header.
+// Functions have to come before the ones that use them, as they do in the
+// output of `--print-code`, and the last one is returned.
+#[derive(Debug)]
+pub struct Assembler<'vm> {
+    vm: &'vm mut Vm,
+    line: usize,                  // in the listing, for errors
+    unclaimed: Vec<Gc<Function>>, // assembled but not used by another function yet
+    section: Option<Section>,
+    assembled: Option<Gc<Function>>,
+}
+
+// The function currently being assembled
+#[derive(Debug)]
+struct Section {
+    function: Function,
+    constants: Vec<Option<Value>>, // constants can be used before earlier ones
+    labels: FnvHashMap<String, usize>,
+    jumps: Vec<Jump>,
+    line: usize,          // the source line in the left-hand column
+    captures_left: usize, // `local` or `upvalue` lines still expected after OP_CLOSURE
+}
+
+// A jump whose offset is filled in once every label is known
+#[derive(Debug)]
+struct Jump {
+    operand: usize, // where the offset goes
+    width: usize,   // of the offset, in bytes
+    backwards: bool,
+    target: Target,
+    line: usize, // in the listing
+}
+
+#[derive(Debug)]
+enum Target {
+    Offset(usize),
+    Label(String),
+}
+
+type Result<T> = std::result::Result<T, AssembleError>;
+
+impl<'vm> Assembler<'vm> {
+    // Like `Compiler::compile`, the script is kept alive until it's passed to
+    // `Vm::interpret` or dropped
+    pub fn assemble(vm: &'vm mut Vm, listing: &str) -> Result<Root<Gc<Function>>> {
+        let mut assembler = Self {
+            vm,
+            line: 0,
+            unclaimed: Vec::new(),
+            section: None,
+            assembled: None,
+        };
+
+        let result = assembler
+            .listing(listing)
+            .map(|script| assembler.vm.root_function(script));
+        assembler.vm.clear_compiler_roots();
+        result
+    }
+
+    fn listing(&mut self, listing: &str) -> Result<Gc<Function>> {
+        let mut lines = listing.lines().enumerate();
+        while let Some((index, line)) = lines.next() {
+            self.line = index + 1;
+
+            // A string with a newline in it carries on to the next line
+            let mut line = line.to_string();
+            while ends_in_string(&line) {
+                match lines.next() {
+                    Some((_, next)) => {
+                        line.push('\n');
+                        line.push_str(next);
+                    }
+                    None => return Err(self.error("Unterminated string.")),
+                }
+            }
+
+            self.parse_line(&line)?;
+        }
+
+        self.finish_section()?;
+        let script = self
+            .assembled
+            .ok_or_else(|| self.error("There's nothing to assemble."))?;
+
+        // Like a .loxc file, a listing can say anything so the VM can't trust it
+        script.verify().map_err(|error| {
+            self.error(format!(
+                "Invalid bytecode at offset {:04} in {}: {}",
+                error.offset, error.function, error.message
+            ))
+        })?;
+        Ok(script)
+    }
+
+    fn error(&self, message: impl Into<String>) -> AssembleError {
+        AssembleError {
+            line: self.line,
+            message: message.into(),
+        }
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<()> {
+        if let Some(header) = line.trim().strip_prefix("==") {
+            let header = header
+                .strip_suffix("==")
+                .ok_or_else(|| self.error("Expect '==' after function name."))?;
+            self.finish_section()?;
+            return self.header(header.trim());
+        }
+
+        let mut tokens = tokenize(line).map_err(|message| self.error(message))?;
+
+        // The offset and line number printed by the disassembler
+        if tokens.len() >= 2 && is_number(&tokens[0]) {
+            if is_number(&tokens[1]) {
+                let line = tokens[1].parse().unwrap();
+                self.section().line = line;
+            } else if tokens[1] != "|" {
+                return Err(self.error("Expect a line number or '|' after the offset."));
+            }
+            tokens.drain(..2);
+        }
+
+        match tokens.as_slice() {
+            [] => Ok(()),
+            [label] if label.ends_with(':') => self.label(&label[..label.len() - 1]),
+            [kind, index] if kind == "local" || kind == "upvalue" => self.capture(kind, index),
+            [name, operands @ ..] => self.instruction(name, operands),
+        }
+    }
+
+    // The rest of a header line, e.g. "<fn add> (arity 2, upvalues 1)"
+    fn header(&mut self, header: &str) -> Result<()> {
+        let (name, details) = match header.split_once(" (") {
+            Some((name, details)) => match details.strip_suffix(')') {
+                Some(details) => (name, details),
+                None => return Err(self.error("Expect ')' after function details.")),
+            },
+            None => (header, ""),
+        };
+
+        let name = if name == "<script>" {
+            None
+        } else if let Some(name) = name.strip_prefix("<fn ").and_then(|n| n.strip_suffix('>')) {
+            let name = self.vm.intern_string(name.to_string());
+            self.vm.push_compiler_root(Value::Obj(Object::Str(name)));
+            Some(name)
+        } else {
+            return Err(self.error(format!("Expect '<script>' or '<fn name>', not '{name}'.")));
+        };
+
+        let mut function = Function::new(name);
+        for detail in details.split(", ").filter(|detail| !detail.is_empty()) {
+            match detail.split_once(' ') {
+                Some(("arity", n)) if is_number(n) => function.arity = n.parse().unwrap(),
+                Some(("upvalues", n)) if is_number(n) => {
+                    function.upvalue_count = n.parse().unwrap()
+                }
+                _ => return Err(self.error(format!("Unknown function detail '{detail}'."))),
+            }
+        }
+
+        self.section = Some(Section::new(function));
+        Ok(())
+    }
+
+    // Lines before the first header belong to the script
+    fn section(&mut self) -> &mut Section {
+        self.section
+            .get_or_insert_with(|| Section::new(Function::new(None)))
+    }
+
+    fn finish_section(&mut self) -> Result<()> {
+        let Some(mut section) = self.section.take() else {
+            return Ok(());
+        };
+
+        if section.captures_left > 0 {
+            return Err(self.error("Expect 'local' or 'upvalue' lines after OP_CLOSURE."));
+        }
+
+        for jump in std::mem::take(&mut section.jumps) {
+            self.patch_jump(&mut section, jump)?;
+        }
+
+        let mut function = section.function;
+        for (index, constant) in section.constants.into_iter().enumerate() {
+            let constant = constant.ok_or_else(|| {
+                self.error(format!("Constant {index} isn't used by any instruction."))
+            })?;
+            function.chunk.constants.push(constant);
+        }
+
+        let function = self.vm.alloc(function);
+        self.vm
+            .push_compiler_root(Value::Obj(Object::Function(function)));
+        self.unclaimed.push(function);
+        self.assembled = Some(function);
+        Ok(())
+    }
+
+    fn patch_jump(&mut self, section: &mut Section, jump: Jump) -> Result<()> {
+        self.line = jump.line;
+
+        let target = match jump.target {
+            Target::Offset(offset) => offset,
+            Target::Label(label) => *section
+                .labels
+                .get(&label)
+                .ok_or_else(|| self.error(format!("Undefined label '{label}'.")))?,
+        };
+
+        let next = jump.operand + jump.width;
+        let distance = if jump.backwards {
+            next.checked_sub(target)
+                .ok_or_else(|| self.error("OP_LOOP can only jump backwards."))?
+        } else {
+            target
+                .checked_sub(next)
+                .ok_or_else(|| self.error("Only OP_LOOP can jump backwards."))?
+        };
+
+        let max = if jump.width == 2 {
+            u16::MAX as usize
+        } else {
+            u32::MAX as usize
+        };
+        if distance > max {
+            return Err(self.error("Too far to jump, use OP_WIDE."));
+        }
+
+        let code = &mut section.function.chunk.code;
+        let bytes = (distance as u32).to_be_bytes();
+        code[jump.operand..next].copy_from_slice(&bytes[4 - jump.width..]);
+        Ok(())
+    }
+
+    fn label(&mut self, label: &str) -> Result<()> {
+        let section = self.section();
+        let offset = section.function.chunk.code.len();
+        if section.labels.insert(label.to_string(), offset).is_some() {
+            return Err(self.error(format!("Label '{label}' is already defined.")));
+        }
+
+        Ok(())
+    }
+
+    // One of the variables captured by the closure before it
+    fn capture(&mut self, kind: &str, index: &str) -> Result<()> {
+        let index = self.number(index)?;
+        let index = u16::try_from(index)
+            .map_err(|_| self.error(format!("{index} is too big for a captured variable.")))?;
+        let section = self.section();
+        if section.captures_left == 0 {
+            return Err(self.error(format!("Unexpected '{kind}' line.")));
+        }
+
+        section.captures_left -= 1;
+        let flags = if kind == "local" { CAPTURE_LOCAL } else { 0 };
+        match u8::try_from(index) {
+            Ok(index) => {
+                self.emit(flags);
+                self.emit(index);
+            }
+            Err(_) => {
+                self.emit(flags | CAPTURE_WIDE);
+                for byte in index.to_be_bytes() {
+                    self.emit(byte);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn instruction(&mut self, name: &str, operands: &[String]) -> Result<()> {
+        use OpCode::*;
+
+        if self.section().captures_left > 0 {
+            return Err(self.error("Expect 'local' or 'upvalue' lines after OP_CLOSURE."));
+        }
+
+        let opcode = opcode(name).ok_or_else(|| self.error(format!("Unknown opcode '{name}'.")))?;
+        self.emit(opcode.into());
+
+        // The long forms take the same operands but a 3 byte constant index
+        let (base, width) = match opcode.short_form() {
+            Some(short) => (short, 3),
+            None => (opcode, 1),
+        };
+
+        match base {
+            Return | Less | Greater | Equal | Not | False | True | Nil | Divide | Multiply
+            | Subtract | Add | Negate | Print | Pop | CloseUpvalue | Inherit => {
+                self.expect_operands(operands, 0)
+            }
+            Constant | DefineGlobal | GetGlobal | SetGlobal | Class | GetProperty | SetProperty
+            | Method | GetSuper => {
+                let index = self.constant(operands)?;
+                self.emit_operand(index, width, opcode)
+            }
+            GetLocal | SetLocal | GetUpvalue | SetUpvalue | Call => {
+                self.expect_operands(operands, 1)?;
+                let operand = self.byte(&operands[0])?;
+                self.emit(operand);
+                Ok(())
+            }
+            Jump | JumpIfFalse | Loop => self.jump(opcode, operands, 2),
+            Invoke | SuperInvoke => {
+                // e.g. `OP_INVOKE (2 args) 0 "method"`
+                let arg_count = match operands {
+                    [count, args, ..] if args == "args)" || args == "arg)" => count
+                        .strip_prefix('(')
+                        .filter(|count| is_number(count))
+                        .ok_or_else(|| self.error("Expect '(N args)' after the opcode."))?,
+                    _ => return Err(self.error("Expect '(N args)' after the opcode.")),
+                };
+                let arg_count = self.byte(arg_count)?;
+
+                let index = self.constant(&operands[2..])?;
+                self.emit_operand(index, width, opcode)?;
+                self.emit(arg_count);
+                Ok(())
+            }
+            Closure => {
+                let index = self.constant(operands)?;
+                self.emit_operand(index, width, opcode)?;
+
+                let section = self.section();
+                section.captures_left = match section.constants[index] {
+                    Some(Value::Obj(Object::Function(function))) => function.upvalue_count,
+                    _ => return Err(self.error("OP_CLOSURE needs a function.")),
+                };
+                Ok(())
+            }
+            Wide => self.wide(operands),
+            ConstantLong | DefineGlobalLong | GetGlobalLong | SetGlobalLong | ClosureLong
+            | ClassLong | GetPropertyLong | SetPropertyLong | MethodLong | InvokeLong
+            | GetSuperLong | SuperInvokeLong => {
+                unreachable!("long forms were mapped to short ones")
+            }
+        }
+    }
+
+    // e.g. `OP_WIDE OP_GET_LOCAL 300` or `OP_WIDE OP_JUMP -> done`
+    fn wide(&mut self, operands: &[String]) -> Result<()> {
+        let (name, operands) = operands
+            .split_first()
+            .ok_or_else(|| self.error("Expect an opcode after OP_WIDE."))?;
+        let opcode = opcode(name)
+            .filter(OpCode::has_wide_form)
+            .ok_or_else(|| self.error(format!("'{name}' has no wide form.")))?;
+        self.emit(opcode.into());
+
+        match opcode {
+            OpCode::GetLocal | OpCode::SetLocal => {
+                self.expect_operands(operands, 1)?;
+                let slot = self.number(&operands[0])?;
+                self.emit_operand(slot, 2, opcode)
+            }
+            _ => self.jump(opcode, operands, 4),
+        }
+    }
+
+    // e.g. `OP_JUMP 8 -> 20`, where 8 is the jump's own offset, or `OP_LOOP -> top`
+    fn jump(&mut self, opcode: OpCode, operands: &[String], width: usize) -> Result<()> {
+        let target = match operands {
+            [arrow, target] | [_, arrow, target] if arrow == "->" => target,
+            _ => return Err(self.error("Expect '-> offset' or '-> label' after the opcode.")),
+        };
+
+        let target = if is_number(target) {
+            Target::Offset(target.parse().unwrap())
+        } else {
+            Target::Label(target.clone())
+        };
+
+        let line = self.line;
+        let section = self.section();
+        section.jumps.push(Jump {
+            operand: section.function.chunk.code.len(),
+            width,
+            backwards: matches!(opcode, OpCode::Loop),
+            target,
+            line,
+        });
+
+        for _ in 0..width {
+            self.emit(0);
+        }
+        Ok(())
+    }
+
+    // Parses a constant operand, which is a value optionally preceded by its
+    // index, and returns the index. Functions are taken from those that have
+    // already been assembled.
+    fn constant(&mut self, operands: &[String]) -> Result<usize> {
+        let (index, value) = match operands {
+            [value] => (None, value),
+            [index, value] => (Some(self.number(index)?), value),
+            _ => return Err(self.error("Expect a constant, optionally after its index.")),
+        };
+
+        let constants = &self.section().constants;
+        let existing = index.and_then(|index| constants.get(index).copied().flatten());
+
+        let value = match (existing, value.strip_prefix("<fn ")) {
+            // The same function can be used by several instructions
+            (Some(existing @ Value::Obj(Object::Function(function))), Some(_))
+                if function.to_string() == *value =>
+            {
+                existing
+            }
+            (_, Some(_)) => self.claim_function(value)?,
+            _ => self.value(value)?,
+        };
+
+        let constants = &mut self.section().constants;
+        let index = match index {
+            Some(index) => index,
+            None => match constants.iter().position(|constant| {
+                *constant == Some(value) && !matches!(value, Value::Obj(Object::Function(_)))
+            }) {
+                Some(index) => index,
+                None => constants.len(),
+            },
+        };
+
+        if index >= constants.len() {
+            constants.resize(index + 1, None);
+        }
+
+        match constants[index] {
+            Some(existing) if existing != value => {
+                Err(self.error(format!("Constant {index} is already {existing}.")))
+            }
+            _ => {
+                constants[index] = Some(value);
+                Ok(index)
+            }
+        }
+    }
+
+    fn claim_function(&mut self, name: &str) -> Result<Value> {
+        let position = self
+            .unclaimed
+            .iter()
+            .position(|function| function.to_string() == name)
+            .ok_or_else(|| self.error(format!("{name} has to be assembled before it's used.")))?;
+
+        let function = self.unclaimed.remove(position);
+        Ok(Value::Obj(Object::Function(function)))
+    }
+
+    fn value(&mut self, value: &str) -> Result<Value> {
+        let value = match value {
+            "nil" => Value::Nil,
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            _ if value.starts_with('"') => {
+                let string = value[1..value.len() - 1].to_string();
+                let string = Value::Obj(Object::Str(self.vm.intern_string(string)));
+                self.vm.push_compiler_root(string);
+                string
+            }
+            _ => match value.parse() {
+                Ok(number) => Value::Number(number),
+                Err(_) => return Err(self.error(format!("Invalid constant '{value}'."))),
+            },
+        };
+
+        Ok(value)
+    }
+
+    fn expect_operands(&self, operands: &[String], count: usize) -> Result<()> {
+        if operands.len() != count {
+            return Err(self.error(format!(
+                "Expect {count} operand(s) but got {}.",
+                operands.len()
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn number(&self, token: &str) -> Result<usize> {
+        token
+            .parse()
+            .map_err(|_| self.error(format!("Expect a number, not '{token}'.")))
+    }
+
+    fn byte(&self, token: &str) -> Result<u8> {
+        let number = self.number(token)?;
+        u8::try_from(number).map_err(|_| self.error(format!("{number} doesn't fit in a byte.")))
+    }
+
+    // Writes a big-endian operand `width` bytes wide
+    fn emit_operand(&mut self, operand: usize, width: usize, opcode: OpCode) -> Result<()> {
+        if operand >> (8 * width) != 0 {
+            let hint = match opcode.long_form() {
+                Some(long) => format!(", use {}", long.name()),
+                None => String::new(),
+            };
+            return Err(self.error(format!("{operand} is too big for {}{hint}.", opcode.name())));
+        }
+
+        for i in (0..width).rev() {
+            self.emit((operand >> (8 * i)) as u8);
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, byte: u8) {
+        let section = self.section();
+        let line = section.line;
+        section
+            .function
+            .chunk
+            .write_byte(byte, line, Span::default());
+    }
+}
+
+impl Section {
+    fn new(function: Function) -> Self {
+        Self {
+            function,
+            constants: Vec::new(),
+            labels: FnvHashMap::default(),
+            jumps: Vec::new(),
+            line: 1,
+            captures_left: 0,
+        }
+    }
+}
+
+fn opcode(name: &str) -> Option<OpCode> {
+    (0..=u8::MAX)
+        .filter_map(|byte| OpCode::try_from(byte).ok())
+        .find(|opcode| opcode.name() == name)
+}
+
+fn is_number(token: &str) -> bool {
+    !token.is_empty() && token.bytes().all(|byte| byte.is_ascii_digit())
+}
+
+// Splits a line on whitespace, keeping strings and `<fn name>` together
+// Whether a string is left open at the end of the line, which means that it
+// has a newline in it. Quotes in a comment don't count.
+fn ends_in_string(line: &str) -> bool {
+    let mut in_string = false;
+    for c in line.chars() {
+        match c {
+            '"' => in_string = !in_string,
+            ';' if !in_string => break,
+            _ => {}
+        }
+    }
+    in_string
+}
+
+fn tokenize(line: &str) -> std::result::Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = line.char_indices().peekable();
+
+    while let Some(&(start, c)) = chars.peek() {
+        let end = match c {
+            ';' => break,
+            _ if c.is_whitespace() => {
+                chars.next();
+                continue;
+            }
+            '"' | '<' => {
+                let close = if c == '"' { '"' } else { '>' };
+                chars.next();
+                match chars.find(|&(_, c)| c == close) {
+                    Some((end, _)) => end + 1,
+                    None => return Err(format!("Expect '{close}'.")),
+                }
+            }
+            _ => {
+                while chars.next_if(|(_, c)| !c.is_whitespace()).is_some() {}
+                chars.peek().map_or(line.len(), |&(end, _)| end)
+            }
+        };
+
+        tokens.push(line[start..end].to_string());
+    }
+
+    Ok(tokens)
+}
+
+mod tests {
+    #[allow(unused)]
+    use super::*;
+
+    #[test]
+    fn assembles_compiler_output() {
+        let mut vm = Vm::new();
+        let source = "
+            fun add(a, b) { return a + b; }
+            var i = 0;
+            while (i < 3) { i = add(i, 1); }
+        ";
+        let compiled = crate::Compiler::compile(&mut vm, source).unwrap();
+
+        // As printed by `--print-code`
+        let listing = "
+            == <fn add> (arity 2) ==
+            0000    2 OP_GET_LOCAL        1
+            0002    | OP_GET_LOCAL        2
+            0004    | OP_ADD
+            0005    | OP_RETURN
+            0006    | OP_NIL
+            0007    | OP_RETURN
+
+            == <script> ==
+            0000    2 OP_CLOSURE          1 <fn add>
+            0002    | OP_DEFINE_GLOBAL    0 \"add\"
+            0004    3 OP_CONSTANT         3 0
+            0006    | OP_DEFINE_GLOBAL    2 \"i\"
+            0008    4 OP_GET_GLOBAL       4 \"i\"
+            0010    | OP_CONSTANT         5 3
+            0012    | OP_LESS
+            0013    | OP_JUMP_IF_FALSE   13 -> 31
+            0016    | OP_POP
+            0017    | OP_GET_GLOBAL       7 \"add\"
+            0019    | OP_GET_GLOBAL       8 \"i\"
+            0021    | OP_CONSTANT         9 1
+            0023    | OP_CALL             2
+            0025    | OP_SET_GLOBAL       6 \"i\"
+            0027    | OP_POP
+            0028    | OP_LOOP            28 -> 8
+            0031    | OP_POP
+            0032    5 OP_NIL
+            0033    | OP_RETURN
+        ";
+        let assembled = Assembler::assemble(&mut vm, listing).unwrap();
+
+        // Functions can't be compared directly since they're different objects
+        let constants = |function: &Function| -> Vec<String> {
+            let constants = function.chunk.constants.iter();
+            constants.map(Value::to_string).collect()
+        };
+        let add = |function: &Function| function.chunk.constants[1].as_object().as_function();
+
+        assert_eq!(compiled.chunk.code, assembled.chunk.code);
+        assert_eq!(compiled.chunk.lines, assembled.chunk.lines);
+        assert_eq!(constants(&compiled), constants(&assembled));
+        assert_eq!(add(&compiled).chunk.code, add(&assembled).chunk.code);
+        assert_eq!(add(&compiled).arity, add(&assembled).arity);
+    }
+
+    #[test]
+    fn assembles_wide_captures() {
+        let mut vm = Vm::new();
+        let locals: String = (0..300).map(|i| format!("var v{i};\n")).collect();
+        let source = format!("{{\n{locals}fun get() {{ return v299; }}\n}}");
+        let compiled = crate::Compiler::compile(&mut vm, &source).unwrap();
+
+        let listing = compiled.nested_disassembly();
+        assert!(listing.contains(" local 300\n")); // slot 0 is reserved
+
+        let assembled = Assembler::assemble(&mut vm, &listing).unwrap();
+        assert_eq!(compiled.chunk.code, assembled.chunk.code);
+    }
+
+    #[test]
+    fn assembles_labels() {
+        let mut vm = Vm::new();
+        let listing = "
+            OP_CONSTANT 0        ; i = 0
+        top:
+            OP_GET_LOCAL 1
+            OP_CONSTANT 3
+            OP_LESS
+            OP_JUMP_IF_FALSE -> done
+            OP_POP
+            OP_GET_LOCAL 1       ; i = i + 1
+            OP_CONSTANT 1
+            OP_ADD
+            OP_SET_LOCAL 1
+            OP_POP
+            OP_LOOP -> top
+        done:
+            OP_POP
+            OP_GET_LOCAL 1
+            OP_DEFINE_GLOBAL \"result\"
+            OP_NIL
+            OP_RETURN
+        ";
+        let function = Assembler::assemble(&mut vm, listing).unwrap();
+        assert_eq!(Ok(()), function.verify());
+
+        vm.interpret(function).unwrap();
+        assert_eq!(Some(3.0), vm.get_global("result").unwrap().as_number());
+    }
+
+    #[test]
+    fn reports_mistakes() {
+        let mut vm = Vm::new();
+        let error =
+            |vm: &mut Vm, listing| Assembler::assemble(vm, listing).unwrap_err().to_string();
+
+        assert_eq!(
+            "[line 2] Error: Unknown opcode 'OP_NOPE'.",
+            error(&mut vm, "OP_NIL\nOP_NOPE")
+        );
+        assert_eq!(
+            "[line 1] Error: Undefined label 'nowhere'.",
+            error(&mut vm, "OP_JUMP -> nowhere")
+        );
+        assert_eq!(
+            "[line 1] Error: <fn f> has to be assembled before it's used.",
+            error(&mut vm, "OP_CLOSURE <fn f>")
+        );
+        assert_eq!(
+            "[line 3] Error: Invalid bytecode at offset 0000 in script: \
+             Instruction pops more values than are on the stack.",
+            error(&mut vm, "OP_POP\nOP_POP\nOP_RETURN")
+        );
+        assert_eq!(
+            "[line 3] Error: Invalid bytecode at offset 0000 in script: \
+             The script can't have parameters or upvalues.",
+            error(&mut vm, "== <script> (arity 1) ==\nOP_NIL\nOP_RETURN\n")
+        );
+
+        // A quote in a comment doesn't start a string
+        assert!(Assembler::assemble(&mut vm, "OP_NIL ; the \"\nOP_RETURN").is_ok());
+    }
+}
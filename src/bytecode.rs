@@ -0,0 +1,432 @@
+use std::hash::Hasher;
+use std::rc::Rc;
+
+use fnv::FnvHasher;
+
+use crate::chunk::{Chunk, LineRun, SpanRun};
+use crate::error::LoadError;
+use crate::function::Function;
+use crate::gc::{Gc, Root};
+use crate::object::Object;
+use crate::value::Value;
+use crate::vm::Vm;
+
+// A compiled script is saved as:
+//
+//   magic    "LOXC"
+//   version  u16
+//   checksum u64, of everything after it
+//   source   optional string, so that runtime errors can still show snippets
+//   script   function
+//
+// where a function is its name, arity, upvalue count, code, constants and
+// source locations. Functions declared inside it are among its constants.
+// Integers are big-endian, like instruction operands, and strings and lists
+// are prefixed with their length as a u32.
+pub const MAGIC: &[u8; 4] = b"LOXC";
+
+// Bump this whenever the format or the meaning of any opcode changes
+pub const VERSION: u16 = 1;
+
+const HEADER_SIZE: usize = MAGIC.len() + 2 + 8;
+
+// Constant tags
+const NIL: u8 = 0;
+const FALSE: u8 = 1;
+const TRUE: u8 = 2;
+const NUMBER: u8 = 3;
+const STRING: u8 = 4;
+const FUNCTION: u8 = 5;
+
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+pub fn serialize(script: &Function) -> Vec<u8> {
+    let mut writer = Writer::default();
+    writer.option(script.chunk.source.as_deref(), Writer::str);
+    writer.function(script);
+
+    let mut bytes = Vec::with_capacity(HEADER_SIZE + writer.bytes.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&VERSION.to_be_bytes());
+    bytes.extend_from_slice(&checksum(&writer.bytes).to_be_bytes());
+    bytes.extend_from_slice(&writer.bytes);
+    bytes
+}
+
+// Like `Compiler::compile`, the script is kept alive until it's passed to
+// `Vm::interpret` or dropped
+pub fn deserialize(vm: &mut Vm, bytes: &[u8]) -> Result<Root<Gc<Function>>, LoadError> {
+    if bytes.len() < HEADER_SIZE || !is_bytecode(bytes) {
+        return Err(LoadError::NotBytecode);
+    }
+
+    let version = u16::from_be_bytes([bytes[4], bytes[5]]);
+    if version != VERSION {
+        return Err(LoadError::UnsupportedVersion(version));
+    }
+
+    let expected = u64::from_be_bytes(bytes[6..HEADER_SIZE].try_into().unwrap());
+    let payload = &bytes[HEADER_SIZE..];
+    if checksum(payload) != expected {
+        return Err(LoadError::ChecksumMismatch);
+    }
+
+    let mut reader = Reader {
+        vm,
+        bytes: payload,
+        source: None,
+    };
+    let result = reader
+        .script()
+        .map(|script| reader.vm.root_function(script));
+    reader.vm.clear_compiler_roots();
+    result
+}
+
+fn checksum(bytes: &[u8]) -> u64 {
+    let mut hasher = FnvHasher::default();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+#[derive(Default)]
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn len(&mut self, len: usize) {
+        self.u32(len.try_into().expect("too big to serialize"));
+    }
+
+    fn str(&mut self, string: &str) {
+        self.len(string.len());
+        self.bytes.extend_from_slice(string.as_bytes());
+    }
+
+    fn option<T: ?Sized>(&mut self, value: Option<&T>, write: impl Fn(&mut Self, &T)) {
+        match value {
+            Some(value) => {
+                self.u8(1);
+                write(self, value);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    fn function(&mut self, function: &Function) {
+        self.option(function.name.as_deref(), |writer, name| {
+            writer.str(name.as_str())
+        });
+        self.len(function.arity);
+        self.len(function.upvalue_count);
+        self.chunk(&function.chunk);
+    }
+
+    fn chunk(&mut self, chunk: &Chunk) {
+        self.len(chunk.code.len());
+        self.bytes.extend_from_slice(&chunk.code);
+
+        self.len(chunk.constants.len());
+        for constant in &chunk.constants {
+            self.constant(constant);
+        }
+
+        self.len(chunk.lines.len());
+        for run in &chunk.lines {
+            self.u32(run.offset);
+            self.u32(run.line);
+        }
+
+        self.len(chunk.spans.len());
+        for run in &chunk.spans {
+            self.u32(run.offset);
+            self.u32(run.start);
+            self.u32(run.end);
+        }
+    }
+
+    fn constant(&mut self, constant: &Value) {
+        match constant {
+            Value::Nil => self.u8(NIL),
+            Value::Bool(false) => self.u8(FALSE),
+            Value::Bool(true) => self.u8(TRUE),
+            Value::Number(number) => {
+                self.u8(NUMBER);
+                self.bytes
+                    .extend_from_slice(&number.to_bits().to_be_bytes());
+            }
+            Value::Obj(Object::Str(string)) => {
+                self.u8(STRING);
+                self.str(string.as_str());
+            }
+            Value::Obj(Object::Function(function)) => {
+                self.u8(FUNCTION);
+                self.function(function);
+            }
+            // The compiler never puts anything else in a chunk
+            Value::Obj(object) => unreachable!("can't serialize {object}"),
+        }
+    }
+}
+
+struct Reader<'a> {
+    vm: &'a mut Vm,
+    bytes: &'a [u8], // what's left to read
+    source: Option<Rc<str>>,
+}
+
+impl Reader<'_> {
+    fn script(&mut self) -> Result<Gc<Function>, LoadError> {
+        if self.u8()? == 1 {
+            self.source = Some(Rc::from(self.str()?));
+        }
+
+        let script = self.function()?;
+        if !self.bytes.is_empty() {
+            return Err(LoadError::Malformed("trailing bytes after the script"));
+        }
+
+        // The checksum only catches accidental damage
+        script.verify().map_err(LoadError::Invalid)?;
+        Ok(script)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&[u8], LoadError> {
+        if len > self.bytes.len() {
+            return Err(LoadError::Malformed("unexpected end of file"));
+        }
+
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, LoadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, LoadError> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn len(&mut self) -> Result<usize, LoadError> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn str(&mut self) -> Result<&str, LoadError> {
+        let len = self.len()?;
+        std::str::from_utf8(self.take(len)?).map_err(|_| LoadError::Malformed("invalid UTF-8"))
+    }
+
+    // Interns a string, keeping it alive until the whole script has been loaded
+    fn string(&mut self) -> Result<Value, LoadError> {
+        let string = self.str()?.to_string();
+        let value = Value::Obj(Object::Str(self.vm.intern_string(string)));
+        self.vm.push_compiler_root(value);
+        Ok(value)
+    }
+
+    fn function(&mut self) -> Result<Gc<Function>, LoadError> {
+        let name = match self.u8()? {
+            0 => None,
+            _ => Some(self.string()?.as_object().as_string()),
+        };
+
+        let mut function = Function::new(name);
+        function.arity = self.len()?;
+        function.upvalue_count = self.len()?;
+        function.chunk = self.chunk()?;
+
+        let function = self.vm.alloc(function);
+        self.vm
+            .push_compiler_root(Value::Obj(Object::Function(function)));
+        Ok(function)
+    }
+
+    fn chunk(&mut self) -> Result<Chunk, LoadError> {
+        let mut chunk = Chunk::new();
+        chunk.source = self.source.clone();
+
+        let len = self.len()?;
+        chunk.code = self.take(len)?.to_vec();
+
+        for _ in 0..self.len()? {
+            let constant = self.constant()?;
+            chunk.constants.push(constant);
+        }
+
+        for _ in 0..self.len()? {
+            let offset = self.u32()?;
+            let line = self.u32()?;
+            chunk.lines.push(LineRun { offset, line });
+        }
+
+        for _ in 0..self.len()? {
+            let offset = self.u32()?;
+            let start = self.u32()?;
+            let end = self.u32()?;
+            chunk.spans.push(SpanRun { offset, start, end });
+        }
+
+        // Every byte needs a location for `line_at` and `span_at` to find
+        let line_offsets: Vec<_> = chunk.lines.iter().map(|run| run.offset).collect();
+        let span_offsets: Vec<_> = chunk.spans.iter().map(|run| run.offset).collect();
+        if !covers_code(chunk.code.len(), &line_offsets)
+            || !covers_code(chunk.code.len(), &span_offsets)
+        {
+            return Err(LoadError::Malformed("invalid source locations"));
+        }
+
+        // Error snippets are cut out of the source using the spans
+        if let Some(source) = &chunk.source {
+            let in_source = |run: &SpanRun| {
+                let (start, end) = (run.start as usize, run.end as usize);
+                start <= end
+                    && end <= source.len()
+                    && source.is_char_boundary(start)
+                    && source.is_char_boundary(end)
+            };
+
+            if !chunk.spans.iter().all(in_source) {
+                return Err(LoadError::Malformed("source locations outside the source"));
+            }
+        }
+
+        Ok(chunk)
+    }
+
+    fn constant(&mut self) -> Result<Value, LoadError> {
+        match self.u8()? {
+            NIL => Ok(Value::Nil),
+            FALSE => Ok(Value::Bool(false)),
+            TRUE => Ok(Value::Bool(true)),
+            NUMBER => {
+                let bits = u64::from_be_bytes(self.take(8)?.try_into().unwrap());
+                Ok(Value::Number(f64::from_bits(bits)))
+            }
+            STRING => self.string(),
+            FUNCTION => Ok(Value::Obj(Object::Function(self.function()?))),
+            _ => Err(LoadError::Malformed("unknown constant type")),
+        }
+    }
+}
+
+// Whether runs starting at `offsets` give each byte of the code one location:
+// the first run starts at zero and the rest are in order inside the code
+fn covers_code(code_len: usize, offsets: &[u32]) -> bool {
+    let starts_at_zero = offsets.first().map_or(code_len == 0, |&offset| offset == 0);
+    let in_order = offsets.windows(2).all(|pair| pair[0] < pair[1]);
+    let inside = offsets
+        .last()
+        .is_none_or(|&offset| (offset as usize) < code_len);
+
+    starts_at_zero && in_order && inside
+}
+
+mod tests {
+    #[allow(unused)]
+    use super::*;
+
+    #[allow(unused)]
+    const SCRIPT: &str = "
+        class Counter {
+            init(start) { this.count = start; }
+            next() { this.count = this.count + 1; return this.count; }
+        }
+
+        fun greet(name) { return \"hello \" + name; }
+
+        var counter = Counter(41);
+        var answer = counter.next();
+        var greeting = greet(\"world\");
+    ";
+
+    #[test]
+    fn round_trips_through_bytes() {
+        let mut vm = Vm::new();
+        let script = crate::Compiler::compile(&mut vm, SCRIPT).unwrap();
+        let bytes = serialize(&script);
+
+        // Load it into a different VM to make sure nothing is shared
+        let mut vm = Vm::new();
+        let script = deserialize(&mut vm, &bytes).unwrap();
+        vm.interpret(script).unwrap();
+
+        assert_eq!(Some(42.0), vm.get_global("answer").unwrap().as_number());
+        assert_eq!(
+            Some("hello world"),
+            vm.get_global("greeting").unwrap().as_str()
+        );
+    }
+
+    #[test]
+    fn rejects_damaged_files() {
+        let mut vm = Vm::new();
+        let script = crate::Compiler::compile(&mut vm, SCRIPT).unwrap();
+        let bytes = serialize(&script);
+
+        let mut corrupt = bytes.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            Err(LoadError::ChecksumMismatch),
+            deserialize(&mut vm, &corrupt)
+        );
+
+        let mut newer = bytes.clone();
+        newer[4..6].copy_from_slice(&(VERSION + 1).to_be_bytes());
+        assert_eq!(
+            Err(LoadError::UnsupportedVersion(VERSION + 1)),
+            deserialize(&mut vm, &newer)
+        );
+
+        assert_eq!(
+            Err(LoadError::NotBytecode),
+            deserialize(&mut vm, SCRIPT.as_bytes())
+        );
+
+        // Returns without anything to return
+        let mut invalid = Function::new(None);
+        invalid
+            .chunk
+            .write_opcode(crate::OpCode::Return, 1, Default::default());
+        assert!(matches!(
+            deserialize(&mut vm, &serialize(&invalid)),
+            Err(LoadError::Invalid(_))
+        ));
+
+        // Points past the end of the source
+        let mut outside = Function::new(None);
+        let span = crate::token::Span { start: 5, end: 6 };
+        outside.chunk.write_opcode(crate::OpCode::Nil, 1, span);
+        outside.chunk.write_opcode(crate::OpCode::Return, 1, span);
+        outside.chunk.source = Some(Rc::from(""));
+        assert!(matches!(
+            deserialize(&mut vm, &serialize(&outside)),
+            Err(LoadError::Malformed(_))
+        ));
+
+        // A script that takes an argument, with a checksum to match. The
+        // arity comes after the source and the script's missing name.
+        let mut with_parameter = bytes.clone();
+        let arity = HEADER_SIZE + 1 + 4 + SCRIPT.len() + 1;
+        assert_eq!(0u32.to_be_bytes(), bytes[arity..arity + 4]);
+        with_parameter[arity..arity + 4].copy_from_slice(&1u32.to_be_bytes());
+        let checksum = checksum(&with_parameter[HEADER_SIZE..]);
+        with_parameter[6..HEADER_SIZE].copy_from_slice(&checksum.to_be_bytes());
+        assert!(matches!(
+            deserialize(&mut vm, &with_parameter),
+            Err(LoadError::Invalid(_))
+        ));
+    }
+}
@@ -1,8 +1,12 @@
+use std::rc::Rc;
+
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 
+use crate::error::Snippet;
+use crate::token::Span;
 use crate::value::{Value, ValueArray};
 
-#[derive(Debug, IntoPrimitive, TryFromPrimitive)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, IntoPrimitive, TryFromPrimitive)]
 #[repr(u8)]
 pub enum OpCode {
     Return = 0,
@@ -29,6 +33,32 @@ pub enum OpCode {
     JumpIfFalse = 21,
     Jump = 22,
     Loop = 23,
+    Call = 24,
+    Closure = 25,
+    GetUpvalue = 26,
+    SetUpvalue = 27,
+    CloseUpvalue = 28,
+    Class = 29,
+    GetProperty = 30,
+    SetProperty = 31,
+    Method = 32,
+    Invoke = 33,
+    Inherit = 34,
+    GetSuper = 35,
+    SuperInvoke = 36,
+    ConstantLong = 37,
+    DefineGlobalLong = 38,
+    GetGlobalLong = 39,
+    SetGlobalLong = 40,
+    Wide = 41,
+    ClosureLong = 42,
+    ClassLong = 43,
+    GetPropertyLong = 44,
+    SetPropertyLong = 45,
+    MethodLong = 46,
+    InvokeLong = 47,
+    GetSuperLong = 48,
+    SuperInvokeLong = 49,
 }
 
 impl OpCode {
@@ -58,15 +88,113 @@ impl OpCode {
             OpCode::JumpIfFalse => "OP_JUMP_IF_FALSE",
             OpCode::Jump => "OP_JUMP",
             OpCode::Loop => "OP_LOOP",
+            OpCode::Call => "OP_CALL",
+            OpCode::Closure => "OP_CLOSURE",
+            OpCode::GetUpvalue => "OP_GET_UPVALUE",
+            OpCode::SetUpvalue => "OP_SET_UPVALUE",
+            OpCode::CloseUpvalue => "OP_CLOSE_UPVALUE",
+            OpCode::Class => "OP_CLASS",
+            OpCode::GetProperty => "OP_GET_PROPERTY",
+            OpCode::SetProperty => "OP_SET_PROPERTY",
+            OpCode::Method => "OP_METHOD",
+            OpCode::Invoke => "OP_INVOKE",
+            OpCode::Inherit => "OP_INHERIT",
+            OpCode::GetSuper => "OP_GET_SUPER",
+            OpCode::SuperInvoke => "OP_SUPER_INVOKE",
+            OpCode::ConstantLong => "OP_CONSTANT_LONG",
+            OpCode::DefineGlobalLong => "OP_DEFINE_GLOBAL_LONG",
+            OpCode::GetGlobalLong => "OP_GET_GLOBAL_LONG",
+            OpCode::SetGlobalLong => "OP_SET_GLOBAL_LONG",
+            OpCode::Wide => "OP_WIDE",
+            OpCode::ClosureLong => "OP_CLOSURE_LONG",
+            OpCode::ClassLong => "OP_CLASS_LONG",
+            OpCode::GetPropertyLong => "OP_GET_PROPERTY_LONG",
+            OpCode::SetPropertyLong => "OP_SET_PROPERTY_LONG",
+            OpCode::MethodLong => "OP_METHOD_LONG",
+            OpCode::InvokeLong => "OP_INVOKE_LONG",
+            OpCode::GetSuperLong => "OP_GET_SUPER_LONG",
+            OpCode::SuperInvokeLong => "OP_SUPER_INVOKE_LONG",
+        }
+    }
+
+    // The variant of the instruction that takes a 24-bit constant index, for
+    // when the chunk has more than 256 constants
+    pub fn long_form(&self) -> Option<OpCode> {
+        match self {
+            OpCode::Constant => Some(OpCode::ConstantLong),
+            OpCode::DefineGlobal => Some(OpCode::DefineGlobalLong),
+            OpCode::GetGlobal => Some(OpCode::GetGlobalLong),
+            OpCode::SetGlobal => Some(OpCode::SetGlobalLong),
+            OpCode::Closure => Some(OpCode::ClosureLong),
+            OpCode::Class => Some(OpCode::ClassLong),
+            OpCode::GetProperty => Some(OpCode::GetPropertyLong),
+            OpCode::SetProperty => Some(OpCode::SetPropertyLong),
+            OpCode::Method => Some(OpCode::MethodLong),
+            OpCode::Invoke => Some(OpCode::InvokeLong),
+            OpCode::GetSuper => Some(OpCode::GetSuperLong),
+            OpCode::SuperInvoke => Some(OpCode::SuperInvokeLong),
+            _ => None,
+        }
+    }
+
+    // The opposite of `long_form`: the instruction that a long one is a
+    // variant of
+    pub fn short_form(&self) -> Option<OpCode> {
+        match self {
+            OpCode::ConstantLong => Some(OpCode::Constant),
+            OpCode::DefineGlobalLong => Some(OpCode::DefineGlobal),
+            OpCode::GetGlobalLong => Some(OpCode::GetGlobal),
+            OpCode::SetGlobalLong => Some(OpCode::SetGlobal),
+            OpCode::ClosureLong => Some(OpCode::Closure),
+            OpCode::ClassLong => Some(OpCode::Class),
+            OpCode::GetPropertyLong => Some(OpCode::GetProperty),
+            OpCode::SetPropertyLong => Some(OpCode::SetProperty),
+            OpCode::MethodLong => Some(OpCode::Method),
+            OpCode::InvokeLong => Some(OpCode::Invoke),
+            OpCode::GetSuperLong => Some(OpCode::GetSuper),
+            OpCode::SuperInvokeLong => Some(OpCode::SuperInvoke),
+            _ => None,
         }
     }
+
+    // Whether the instruction can follow a `Wide` prefix, which doubles the
+    // size of its operand
+    pub fn has_wide_form(&self) -> bool {
+        matches!(
+            self,
+            OpCode::GetLocal | OpCode::SetLocal | OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop
+        )
+    }
 }
 
+// Each variable an `OP_CLOSURE` captures is a flags byte followed by its
+// index, which is two bytes instead of one if `CAPTURE_WIDE` is set
+pub const CAPTURE_LOCAL: u8 = 0x01;
+pub const CAPTURE_WIDE: u8 = 0x80;
+
 #[derive(Debug, Default)]
 pub struct Chunk {
     pub code: Vec<u8>,
-    pub constants: ValueArray,
-    pub lines: Vec<usize>,
+    pub(crate) constants: ValueArray,
+    pub lines: Vec<LineRun>,
+    pub spans: Vec<SpanRun>,
+    pub source: Option<Rc<str>>, // the code the chunk was compiled from
+}
+
+// Source locations are run-length encoded: each run covers the bytes from its
+// offset up to where the next run starts. Lines change much less often than
+// spans (which change with every token) so they're kept separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineRun {
+    pub offset: u32, // the first byte in the run
+    pub line: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpanRun {
+    pub offset: u32, // the first byte in the run
+    pub start: u32,
+    pub end: u32,
 }
 
 impl Chunk {
@@ -74,13 +202,52 @@ impl Chunk {
         Default::default()
     }
 
-    pub fn write_byte(&mut self, byte: u8, line: usize) {
+    pub fn write_byte(&mut self, byte: u8, line: usize, span: Span) {
+        let offset = self.code.len() as u32;
         self.code.push(byte);
-        self.lines.push(line);
+
+        let line = line as u32;
+        if self.lines.last().is_none_or(|run| run.line != line) {
+            self.lines.push(LineRun { offset, line });
+        }
+
+        let (start, end) = (span.start as u32, span.end as u32);
+        if self
+            .spans
+            .last()
+            .is_none_or(|run| (run.start, run.end) != (start, end))
+        {
+            self.spans.push(SpanRun { offset, start, end });
+        }
+    }
+
+    pub fn write_opcode(&mut self, chunk: OpCode, line: usize, span: Span) {
+        self.write_byte(chunk.into(), line, span);
     }
 
-    pub fn write_opcode(&mut self, chunk: OpCode, line: usize) {
-        self.write_byte(chunk.into(), line);
+    // The source line that produced the byte at `offset`, if the source is known
+    pub fn snippet(&self, offset: usize) -> Option<Snippet> {
+        let source = self.source.as_ref()?;
+        Some(Snippet::new(source, self.span_at(offset)))
+    }
+
+    pub fn line_at(&self, offset: usize) -> usize {
+        // The last run that starts at or before `offset`
+        let index = self
+            .lines
+            .partition_point(|run| run.offset as usize <= offset);
+        self.lines[index - 1].line as usize
+    }
+
+    pub fn span_at(&self, offset: usize) -> Span {
+        let index = self
+            .spans
+            .partition_point(|run| run.offset as usize <= offset);
+        let run = self.spans[index - 1];
+        Span {
+            start: run.start as usize,
+            end: run.end as usize,
+        }
     }
 
     pub fn add_constant(&mut self, constant: Value) -> usize {
@@ -91,6 +258,16 @@ impl Chunk {
     pub fn count(&self) -> usize {
         self.code.len()
     }
+
+    // Throws away the code from `offset` onwards, e.g. to replace it with
+    // something shorter
+    pub fn truncate(&mut self, offset: usize) {
+        self.code.truncate(offset);
+
+        let offset = offset as u32;
+        self.lines.retain(|run| run.offset < offset);
+        self.spans.retain(|run| run.offset < offset);
+    }
 }
 
 mod tests {
@@ -101,4 +278,23 @@ mod tests {
     fn correct_size() {
         assert_eq!(1, std::mem::size_of::<OpCode>())
     }
+
+    #[test]
+    fn line_at_finds_runs() {
+        let mut chunk = Chunk::new();
+        let span = Span { start: 0, end: 1 };
+        chunk.write_opcode(OpCode::Nil, 1, span);
+        chunk.write_opcode(OpCode::Pop, 1, span);
+        chunk.write_opcode(OpCode::Constant, 3, span);
+        chunk.write_byte(0, 3, span);
+        chunk.write_opcode(OpCode::Return, 4, span);
+
+        let lines: Vec<_> = (0..chunk.count())
+            .map(|offset| chunk.line_at(offset))
+            .collect();
+        assert_eq!(vec![1, 1, 3, 3, 4], lines);
+        assert_eq!(3, chunk.lines.len());
+        assert_eq!(1, chunk.spans.len());
+        assert_eq!(span, chunk.span_at(3));
+    }
 }
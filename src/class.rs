@@ -0,0 +1,98 @@
+use std::cell::RefCell;
+use std::fmt::Display;
+
+use fnv::FnvHashMap;
+
+use crate::closure::Closure;
+use crate::gc::{Gc, Trace, Tracer};
+use crate::string::LoxString;
+use crate::value::Value;
+
+#[derive(Debug)]
+pub struct Class {
+    pub name: Gc<LoxString>,
+    pub methods: RefCell<FnvHashMap<Gc<LoxString>, Gc<Closure>>>,
+}
+
+impl Class {
+    pub fn new(name: Gc<LoxString>) -> Self {
+        Self {
+            name,
+            methods: Default::default(),
+        }
+    }
+
+    pub fn find_method(&self, name: Gc<LoxString>) -> Option<Gc<Closure>> {
+        self.methods.borrow().get(&name).copied()
+    }
+}
+
+impl Display for Class {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name.as_str())
+    }
+}
+
+impl Trace for Class {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.mark(self.name);
+
+        for (name, method) in self.methods.borrow().iter() {
+            tracer.mark(*name);
+            tracer.mark(*method);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    pub class: Gc<Class>,
+    pub fields: RefCell<FnvHashMap<Gc<LoxString>, Value>>,
+}
+
+impl Instance {
+    pub fn new(class: Gc<Class>) -> Self {
+        Self {
+            class,
+            fields: Default::default(),
+        }
+    }
+}
+
+impl Display for Instance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} instance", self.class.name.as_str())
+    }
+}
+
+impl Trace for Instance {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.mark(self.class);
+
+        for (name, value) in self.fields.borrow().iter() {
+            tracer.mark(*name);
+            tracer.mark_value(value);
+        }
+    }
+}
+
+// A method that has been accessed on an instance but not called yet. It
+// remembers the instance so that `this` works when it's eventually called.
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: Gc<Closure>,
+}
+
+impl Display for BoundMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", *self.method)
+    }
+}
+
+impl Trace for BoundMethod {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.mark_value(&self.receiver);
+        tracer.mark(self.method);
+    }
+}
@@ -0,0 +1,65 @@
+use std::cell::RefCell;
+use std::fmt::Display;
+
+use crate::function::Function;
+use crate::gc::{Gc, Trace, Tracer};
+use crate::value::Value;
+
+#[derive(Debug)]
+pub struct Closure {
+    pub function: Gc<Function>,
+    pub upvalues: Vec<Gc<RefCell<Upvalue>>>,
+}
+
+impl Closure {
+    pub fn new(function: Gc<Function>, upvalues: Vec<Gc<RefCell<Upvalue>>>) -> Self {
+        Self { function, upvalues }
+    }
+}
+
+impl Display for Closure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", *self.function)
+    }
+}
+
+impl Trace for Closure {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.mark(self.function);
+
+        for upvalue in &self.upvalues {
+            tracer.mark(*upvalue);
+        }
+    }
+
+    fn heap_size(&self) -> usize {
+        self.upvalues.capacity() * size_of::<Gc<RefCell<Upvalue>>>()
+    }
+}
+
+// A variable captured by a closure. While the variable is still on the stack,
+// the upvalue refers to its stack slot. Once the variable goes out of scope,
+// the value is moved into the upvalue itself.
+#[derive(Debug)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+impl Upvalue {
+    pub fn open_slot(&self) -> Option<usize> {
+        match self {
+            Upvalue::Open(slot) => Some(*slot),
+            Upvalue::Closed(_) => None,
+        }
+    }
+}
+
+impl Trace for RefCell<Upvalue> {
+    fn trace(&self, tracer: &mut Tracer) {
+        // Open upvalues point at the stack, which is already a root
+        if let Upvalue::Closed(value) = &*self.borrow() {
+            tracer.mark_value(value);
+        }
+    }
+}
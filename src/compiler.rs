@@ -1,64 +1,135 @@
+use std::rc::Rc;
+
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 
-use crate::chunk::{Chunk, OpCode};
+use crate::chunk::{Chunk, OpCode, CAPTURE_LOCAL, CAPTURE_WIDE};
+use crate::error::{CompileError, Snippet};
+use crate::function::Function;
+use crate::gc::{Gc, Root};
 use crate::object::Object;
 use crate::scanner::Scanner;
 use crate::string::LoxString;
-use crate::token::{Token, TokenType};
+use crate::token::{Span, Token, TokenType};
 use crate::value::Value;
-use crate::vm::Vm;
+use crate::vm::{Vm, VmError};
+
+// Compilation stops once this many errors have been found. Past a certain point
+// they're more likely to be caused by earlier errors than to be new problems.
+pub const MAX_COMPILE_ERRORS: usize = 32;
 
 #[derive(Debug)]
 pub struct Compiler<'src, 'vm> {
     vm: &'vm mut Vm,
+    source: Rc<str>, // shared by every chunk compiled from it
     scanner: Scanner<'src>,
     current: Option<Token<'src>>,
     previous: Option<Token<'src>>,
-    had_error: bool,
+    errors: Vec<CompileError>,
     panic_mode: bool,
-    compiling_chunk: Chunk,
-    locals: Locals<'src>,
+    repl: bool,                   // print the value of a trailing expression statement
+    wide_jumps: bool,             // emit every forward jump with a 32-bit offset
+    jump_overflow: bool,          // a forward jump didn't fit in 16 bits
+    gave_up: bool,                // stopped after `MAX_COMPILE_ERRORS` errors
+    listings: String,             // the bytecode of each function, for `print_code`
+    operand_start: Option<usize>, // where the left operand of an infix operator starts
+    state: Box<FunctionState<'src>>,
+    class_state: Option<Box<ClassState>>,
 }
 
 impl<'src, 'vm> Compiler<'src, 'vm> {
     fn new(vm: &'vm mut Vm, source: &'src str) -> Self {
         let scanner = Scanner::new(source);
-        let chunk = Chunk::new();
 
         Self {
             vm,
+            source: Rc::from(source),
             scanner,
             current: None,
             previous: None,
-            had_error: false,
+            errors: Vec::new(),
             panic_mode: false,
-            compiling_chunk: chunk,
-            locals: Locals::new(),
+            repl: false,
+            wide_jumps: false,
+            jump_overflow: false,
+            gave_up: false,
+            listings: String::new(),
+            operand_start: None,
+            state: Box::new(FunctionState::new(FunctionType::Script, None)),
+            class_state: None,
         }
     }
 
-    pub fn compile(vm: &'vm mut Vm, source: &'src str) -> Result<Chunk, ()> {
-        let mut compiler = Self::new(vm, source);
+    // The script is kept alive until it's passed to `Vm::interpret` or dropped
+    pub fn compile(vm: &'vm mut Vm, source: &'src str) -> Result<Root<Gc<Function>>, VmError> {
+        Self::compile_with(vm, source, false)
+    }
 
-        compiler.advance();
-        while !compiler.match_(TokenType::Eof) {
-            compiler.declaration();
-        }
+    // Like `compile` but for a line typed into the REPL: if it ends with an
+    // expression statement then its value is printed, and the semicolon after
+    // it is optional. So `1 + 2` prints 3.
+    pub fn compile_repl(vm: &'vm mut Vm, source: &'src str) -> Result<Root<Gc<Function>>, VmError> {
+        Self::compile_with(vm, source, true)
+    }
 
-        compiler.end_compiler();
+    fn compile_with(
+        vm: &'vm mut Vm,
+        source: &'src str,
+        repl: bool,
+    ) -> Result<Root<Gc<Function>>, VmError> {
+        let mut compiler = Self::new(vm, source);
+        compiler.repl = repl;
+        let mut function = compiler.script();
+
+        // We don't know how far a forward jump goes until after we've emitted
+        // it and by then it's too late to make room for a bigger offset. So if
+        // any of them were too far we start again with all of them made wide.
+        if compiler.jump_overflow {
+            let vm = compiler.vm;
+            vm.clear_compiler_roots();
+
+            compiler = Self::new(vm, source);
+            compiler.repl = repl;
+            compiler.wide_jumps = true;
+            function = compiler.script();
+        }
 
-        if compiler.had_error {
-            Err(())
+        let result = if compiler.had_error() {
+            Err(VmError::CompileError(std::mem::take(&mut compiler.errors)))
         } else {
-            Ok(compiler.compiling_chunk)
+            // Only now do we know that these are the listings that'll run.
+            // Not being able to show them is no reason to fail the compile.
+            let _ = compiler.vm.output().write_all(compiler.listings.as_bytes());
+            let function = compiler.vm.alloc(function);
+            Ok(compiler.vm.root_function(function))
+        };
+
+        compiler.vm.clear_compiler_roots();
+        result
+    }
+
+    fn script(&mut self) -> Function {
+        self.advance();
+        while !self.match_(TokenType::Eof) {
+            self.declaration();
         }
+
+        let (function, _) = self.end_compiler();
+        function
     }
 
     fn advance(&mut self) {
         self.previous = self.current.take();
 
         loop {
-            let token = self.scanner.scan_token();
+            let mut token = self.scanner.scan_token();
+
+            // Once we've given up, pretend that the source ends here so that
+            // whatever we're in the middle of parsing finishes quickly
+            if self.gave_up {
+                token.token_type = TokenType::Eof;
+                token.lexeme = "";
+            }
+
             self.current = Some(token);
             match token.token_type {
                 TokenType::Error => self.error_at_current(token.lexeme),
@@ -67,38 +138,69 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
         }
     }
 
+    fn had_error(&self) -> bool {
+        !self.errors.is_empty()
+    }
+
     fn error_at_current(&mut self, message: &str) {
         self.error_at(&self.current.unwrap(), message);
-        self.had_error = true;
     }
 
     fn error(&mut self, message: &str) {
         self.error_at(&self.previous.unwrap(), message);
-        self.had_error = true;
     }
 
     fn error_at(&mut self, token: &Token, message: &str) {
-        if self.panic_mode {
+        if self.panic_mode || self.gave_up {
             return;
         }
         self.panic_mode = true;
 
-        eprint!("[line {}] Error", token.line);
+        // Error tokens carry the message in place of a lexeme
+        let lexeme = match token.token_type {
+            TokenType::Eof | TokenType::Error => "",
+            _ => token.lexeme,
+        };
+
+        // Point just past the last token rather than at any trailing whitespace
+        let span = match token.token_type {
+            TokenType::Eof => {
+                let end = self.source.trim_end().len();
+                Span { start: end, end }
+            }
+            _ => token.span,
+        };
 
-        match token.token_type {
-            TokenType::Eof => eprint!(" at end"),
-            TokenType::Error => {}
-            _ => eprint!(" at {}", token.lexeme),
+        // Recovering from an error can trip over the same token again
+        if self
+            .errors
+            .iter()
+            .any(|error| error.span == span && error.message == message)
+        {
+            return;
         }
 
-        eprintln!(": {message}");
-        self.had_error = true;
+        // Tokens like strings can span several lines so report where they start
+        let snippet = Snippet::new(&self.source, span);
+        self.errors.push(CompileError {
+            line: snippet.line,
+            column: snippet.column,
+            lexeme: lexeme.to_string(),
+            message: message.to_string(),
+            at_end: token.token_type == TokenType::Eof,
+            span,
+            snippet,
+        });
+
+        if self.errors.len() == MAX_COMPILE_ERRORS {
+            self.gave_up = true;
+        }
     }
 
     fn consume(&mut self, expected: TokenType, message: &str) {
         if self
             .current
-            .map_or(false, |token| token.token_type == expected)
+            .is_some_and(|token| token.token_type == expected)
         {
             self.advance();
         } else {
@@ -108,12 +210,12 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
 
     fn check(&mut self, token_type: TokenType) -> bool {
         self.current
-            .map_or(false, |token| token.token_type == token_type)
+            .is_some_and(|token| token.token_type == token_type)
     }
 
     fn check_previous(&mut self, token_type: TokenType) -> bool {
         self.previous
-            .map_or(false, |token| token.token_type == token_type)
+            .is_some_and(|token| token.token_type == token_type)
     }
 
     fn match_(&mut self, token_type: TokenType) -> bool {
@@ -126,73 +228,175 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
     }
 
     fn current_chunk(&self) -> &Chunk {
-        &self.compiling_chunk
+        &self.state.function.chunk
     }
 
     fn current_chunk_mut(&mut self) -> &mut Chunk {
-        &mut self.compiling_chunk
+        &mut self.state.function.chunk
     }
 
     fn emit_byte(&mut self, byte: u8) {
-        let line = self.previous.unwrap().line;
-        self.current_chunk_mut().write_byte(byte, line);
+        let Token { line, span, .. } = self.previous.unwrap();
+        self.current_chunk_mut().write_byte(byte, line, span);
     }
 
     fn emit_opcode(&mut self, opcode: OpCode) {
-        let line = self.previous.unwrap().line;
-        self.current_chunk_mut().write_opcode(opcode, line);
+        self.emit_opcode_at(opcode, self.previous.unwrap());
     }
 
-    fn emit_opcodes(&mut self, opcode1: OpCode, opcode2: OpCode) {
-        self.emit_opcode(opcode1);
-        self.emit_opcode(opcode2);
+    // Attributes the instruction to `token` rather than the previous token so
+    // that runtime errors point at e.g. an operator instead of its operand
+    fn emit_opcode_at(&mut self, opcode: OpCode, token: Token) {
+        self.current_chunk_mut()
+            .write_opcode(opcode, token.line, token.span);
     }
 
     fn emit_jump(&mut self, opcode: OpCode) -> usize {
+        if self.wide_jumps {
+            self.emit_opcode(OpCode::Wide);
+        }
         self.emit_opcode(opcode);
 
         // Temporary offset to be patched later
-        self.emit_byte(0xff);
-        self.emit_byte(0xff);
+        let width = self.jump_width();
+        for _ in 0..width {
+            self.emit_byte(0xff);
+        }
 
-        self.current_chunk().count() - 2
+        self.current_chunk().count() - width
     }
 
-    fn emit_loop(&mut self, loop_start: usize) {
-        self.emit_opcode(OpCode::Loop);
+    // The number of bytes in a forward jump's offset
+    fn jump_width(&self) -> usize {
+        if self.wide_jumps {
+            4
+        } else {
+            2
+        }
+    }
 
+    fn emit_loop(&mut self, loop_start: usize) {
         // Distance between loop instruction and beginning of loop.
-        // +2 accounts for the operands to Loop.
-        let offset = self.current_chunk().count() - loop_start + 2;
-        if offset > u16::MAX as usize {
-            self.error("Loop body too large.");
+        // +3 accounts for Loop and its operands.
+        let offset = self.current_chunk().count() - loop_start + 3;
+
+        if let Ok(offset) = u16::try_from(offset) {
+            self.emit_opcode(OpCode::Loop);
+            for byte in offset.to_be_bytes() {
+                self.emit_byte(byte);
+            }
+            return;
         }
 
-        let top = ((offset >> 8) & 0xff) as u8;
-        let bottom = (offset & 0xff) as u8;
-        self.emit_byte(top);
-        self.emit_byte(bottom);
+        // The wide form is three bytes longer: the prefix and a bigger offset
+        let Ok(offset) = u32::try_from(offset + 3) else {
+            self.error("Loop body too large.");
+            return;
+        };
+
+        self.emit_opcode(OpCode::Wide);
+        self.emit_opcode(OpCode::Loop);
+        for byte in offset.to_be_bytes() {
+            self.emit_byte(byte);
+        }
     }
 
-    fn end_compiler(&mut self) {
+    fn end_compiler(&mut self) -> (Function, Vec<Upvalue>) {
         self.emit_return();
+        let mut function = std::mem::take(&mut self.state.function);
+        function.chunk.source = Some(Rc::clone(&self.source));
+        let upvalues = std::mem::take(&mut self.state.upvalues);
+
+        if self.vm.print_code() {
+            self.listings += &function.disassembly();
+            self.listings.push('\n');
+        }
 
-        #[cfg(debug_assertions)]
-        if !self.had_error {
-            self.current_chunk().disassemble("code");
-            println!();
+        // Return to the function that encloses this one (if any)
+        if let Some(enclosing) = self.state.enclosing.take() {
+            self.state = enclosing;
         }
+
+        (function, upvalues)
     }
 
     fn emit_return(&mut self) {
+        // Initializers implicitly return the instance (in slot zero)
+        if self.state.function_type == FunctionType::Initializer {
+            self.emit_opcode(OpCode::GetLocal);
+            self.emit_byte(0);
+        } else {
+            self.emit_opcode(OpCode::Nil);
+        }
+
         self.emit_opcode(OpCode::Return);
     }
 
     fn emit_constant(&mut self, value: Value) {
-        self.emit_opcode(OpCode::Constant);
-
         let constant = self.make_constant(value);
-        self.emit_byte(constant);
+        self.emit_with_operand(OpCode::Constant, constant);
+    }
+
+    // Loads a value known at compile time, remembering it so that an operator
+    // applied to it can be folded into a single constant
+    fn emit_value(&mut self, value: Value) {
+        let start = self.current_chunk().count();
+        let constants = self.current_chunk().constants.len();
+
+        match value {
+            Value::Nil => self.emit_opcode(OpCode::Nil),
+            Value::Bool(true) => self.emit_opcode(OpCode::True),
+            Value::Bool(false) => self.emit_opcode(OpCode::False),
+            value => self.emit_constant(value),
+        }
+
+        self.state.last_value = Some(LoadedValue {
+            start,
+            end: self.current_chunk().count(),
+            constants,
+            value,
+        });
+    }
+
+    // The value of the expression whose code starts at `start`, if all that
+    // code does is load a value known at compile time
+    fn value_since(&self, start: usize) -> Option<LoadedValue> {
+        self.state
+            .last_value
+            .filter(|loaded| (loaded.start, loaded.end) == (start, self.current_chunk().count()))
+    }
+
+    // Replaces the code for an expression, starting with `loaded`, with code
+    // that loads its value instead
+    fn fold(&mut self, loaded: LoadedValue, value: Value) {
+        let chunk = self.current_chunk_mut();
+        chunk.truncate(loaded.start);
+        chunk.constants.truncate(loaded.constants);
+
+        self.emit_value(value);
+    }
+
+    // Emits an instruction with a one byte operand, switching to the long or
+    // wide form of the instruction if the operand doesn't fit
+    fn emit_with_operand(&mut self, opcode: OpCode, operand: usize) {
+        if let Ok(operand) = u8::try_from(operand) {
+            self.emit_opcode(opcode);
+            self.emit_byte(operand);
+        } else if let Some(long_opcode) = opcode.long_form() {
+            // Stored big-endian, like jump offsets
+            self.emit_opcode(long_opcode);
+            self.emit_byte((operand >> 16) as u8);
+            self.emit_byte((operand >> 8) as u8);
+            self.emit_byte(operand as u8);
+        } else if let (true, Ok(operand)) = (opcode.has_wide_form(), u16::try_from(operand)) {
+            self.emit_opcode(OpCode::Wide);
+            self.emit_opcode(opcode);
+            for byte in operand.to_be_bytes() {
+                self.emit_byte(byte);
+            }
+        } else {
+            self.error("Too many constants in one chunk.");
+        }
     }
 
     fn expression(&mut self) {
@@ -200,7 +404,11 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
     }
 
     fn declaration(&mut self) {
-        if self.match_(TokenType::Var) {
+        if self.match_(TokenType::Class) {
+            self.class_declaration();
+        } else if self.match_(TokenType::Fun) {
+            self.fun_declaration();
+        } else if self.match_(TokenType::Var) {
             self.variable_declaration();
         } else {
             self.statement();
@@ -218,6 +426,8 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
             self.for_statement();
         } else if self.match_(TokenType::If) {
             self.if_statement();
+        } else if self.match_(TokenType::Return) {
+            self.return_statement();
         } else if self.match_(TokenType::While) {
             self.while_statement();
         } else if self.match_(TokenType::LeftBrace) {
@@ -324,6 +534,24 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
         self.patch_jump(else_jump);
     }
 
+    fn return_statement(&mut self) {
+        if self.state.function_type == FunctionType::Script {
+            self.error("Can't return from top-level code.");
+        }
+
+        if self.match_(TokenType::Semicolon) {
+            self.emit_return();
+        } else {
+            if self.state.function_type == FunctionType::Initializer {
+                self.error("Can't return a value from an initializer.");
+            }
+
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+            self.emit_opcode(OpCode::Return);
+        }
+    }
+
     fn while_statement(&mut self) {
         let loop_start = self.current_chunk().count();
 
@@ -349,18 +577,26 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
     }
 
     fn patch_jump(&mut self, offset: usize) {
-        let jump = self.current_chunk().count() - offset - 2;
+        let width = self.jump_width();
+        let jump = self.current_chunk().count() - offset - width;
 
-        if jump > u16::MAX as usize {
-            self.error("Too much code to jump over.");
+        if !self.wide_jumps && jump > u16::MAX as usize {
+            // `compile` will start over with wide jumps
+            self.jump_overflow = true;
+            return;
         }
 
-        self.current_chunk_mut().code[offset] = ((jump >> 8) & 0xff) as u8;
-        self.current_chunk_mut().code[offset + 1] = (jump & 0xff) as u8;
+        let Ok(jump) = u32::try_from(jump) else {
+            self.error("Too much code to jump over.");
+            return;
+        };
+
+        let bytes = jump.to_be_bytes();
+        self.current_chunk_mut().code[offset..offset + width].copy_from_slice(&bytes[4 - width..]);
     }
 
     fn begin_scope(&mut self) {
-        self.locals.scope_depth += 1;
+        self.state.locals.scope_depth += 1;
     }
 
     fn block(&mut self) {
@@ -372,30 +608,212 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
     }
 
     fn end_scope(&mut self) {
-        self.locals.scope_depth -= 1;
+        self.state.locals.scope_depth -= 1;
 
         // Clean up any local variables in the current scope
-        while let Some(local) = self.locals.locals.last() {
+        while let Some(local) = self.state.locals.locals.last() {
             // Stop if we encounter a parent scope
             // Or global scope (depth == None)
             if local
                 .depth
-                .map_or(true, |depth| depth <= self.locals.scope_depth)
+                .is_none_or(|depth| depth <= self.state.locals.scope_depth)
             {
                 break;
             }
 
-            self.emit_opcode(OpCode::Pop);
-            self.locals.locals.pop();
+            // Captured variables need to be moved off the stack and into
+            // their upvalue so that closures can continue to use them.
+            let is_captured = local.is_captured;
+            if is_captured {
+                self.emit_opcode(OpCode::CloseUpvalue);
+            } else {
+                self.emit_opcode(OpCode::Pop);
+            }
+            self.state.locals.locals.pop();
         }
     }
 
     fn expression_statement(&mut self) {
         self.expression();
+
+        // See `compile_repl`
+        if self.repl
+            && self.state.function_type == FunctionType::Script
+            && self.state.locals.scope_depth == 0
+        {
+            let had_semicolon = self.match_(TokenType::Semicolon);
+            if self.check(TokenType::Eof) {
+                self.emit_opcode(OpCode::Print);
+                return;
+            } else if had_semicolon {
+                self.emit_opcode(OpCode::Pop);
+                return;
+            }
+        }
+
         self.consume(TokenType::Semicolon, "Expect ';' after value.");
         self.emit_opcode(OpCode::Pop);
     }
 
+    fn class_declaration(&mut self) {
+        self.consume(TokenType::Identifier, "Expect class name.");
+        let class_name = self.previous.unwrap();
+        let name_constant = self.identifier_constant(&class_name);
+        self.declare_variable();
+
+        self.emit_with_operand(OpCode::Class, name_constant);
+        self.define_variable(name_constant);
+
+        let enclosing = self.class_state.take();
+        self.class_state = Some(Box::new(ClassState {
+            enclosing,
+            has_superclass: false,
+        }));
+
+        if self.match_(TokenType::Less) {
+            self.consume(TokenType::Identifier, "Expect superclass name.");
+            variable(self, false);
+
+            if class_name.identifiers_equal(&self.previous.unwrap()) {
+                self.error("A class can't inherit from itself.");
+            }
+
+            // Store the superclass in a local named `super` so that each
+            // method can capture it. The new scope keeps `super` from
+            // clashing with the superclasses of other classes.
+            self.begin_scope();
+            let super_token = self.synthetic_token("super");
+            if self.state.locals.add(super_token).is_err() {
+                self.error("Too many local variables in function.");
+            }
+            self.define_variable(0);
+
+            self.named_variable(class_name, false);
+            self.emit_opcode(OpCode::Inherit);
+            self.class_state.as_mut().unwrap().has_superclass = true;
+        }
+
+        // Load the class back onto the stack so that methods can be bound to it
+        self.named_variable(class_name, false);
+
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.");
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.method();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.");
+        self.emit_opcode(OpCode::Pop); // the class
+
+        let class_state = self.class_state.take().unwrap();
+        if class_state.has_superclass {
+            self.end_scope();
+        }
+
+        self.class_state = class_state.enclosing;
+    }
+
+    fn method(&mut self) {
+        self.consume(TokenType::Identifier, "Expect method name.");
+        let name = self.previous.unwrap();
+        let constant = self.identifier_constant(&name);
+
+        let function_type = if name.lexeme == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(function_type);
+
+        self.emit_with_operand(OpCode::Method, constant);
+    }
+
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+
+        // Functions can refer to themselves (recursion) so we mark the name as
+        // initialized before compiling the body.
+        self.state.locals.mark_initialized();
+        self.function(FunctionType::Function);
+        self.define_variable(global);
+    }
+
+    fn function(&mut self, function_type: FunctionType) {
+        let name = LoxString::copy_string(self.vm, self.previous.unwrap().lexeme);
+        self.vm.push_compiler_root(Value::Obj(Object::Str(name)));
+        let enclosing = std::mem::replace(
+            &mut self.state,
+            Box::new(FunctionState::new(function_type, Some(name))),
+        );
+        self.state.enclosing = Some(enclosing);
+
+        // There's no matching `end_scope` because we throw away the
+        // whole `FunctionState` when we're done with it.
+        self.begin_scope();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.");
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.state.function.arity += 1;
+                if self.state.function.arity > u8::MAX as usize {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                }
+
+                let constant = self.parse_variable("Expect parameter name.");
+                self.define_variable(constant);
+
+                if !self.match_(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.");
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
+        self.block();
+
+        let (function, upvalues) = self.end_compiler();
+        let function = self.vm.alloc(function);
+        let value = Value::Obj(Object::Function(function));
+        let constant = self.make_constant(value);
+        self.emit_with_operand(OpCode::Closure, constant);
+
+        // Tell the VM where to find each variable the closure captures
+        for upvalue in upvalues {
+            let flags = if upvalue.is_local { CAPTURE_LOCAL } else { 0 };
+            match u8::try_from(upvalue.index) {
+                Ok(index) => {
+                    self.emit_byte(flags);
+                    self.emit_byte(index);
+                }
+                Err(_) => {
+                    self.emit_byte(flags | CAPTURE_WIDE);
+                    for byte in upvalue.index.to_be_bytes() {
+                        self.emit_byte(byte);
+                    }
+                }
+            }
+        }
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count: usize = 0;
+
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.expression();
+                if arg_count == u8::MAX as usize {
+                    self.error("Can't have more than 255 arguments.");
+                }
+                arg_count += 1;
+
+                if !self.match_(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.");
+        arg_count as u8
+    }
+
     fn variable_declaration(&mut self) {
         let global = self.parse_variable("Expect variable name.");
 
@@ -427,9 +845,9 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
             {
                 return;
             }
-        }
 
-        self.advance();
+            self.advance();
+        }
     }
 
     fn parse_precedence(&mut self, precedence: Precedence) {
@@ -439,6 +857,7 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
             .get_parse_rule(self.previous.unwrap().token_type)
             .prefix;
 
+        let start = self.current_chunk().count();
         let can_assign = match prefix_rule {
             Some(prefix_func) => {
                 let can_assign = precedence <= Precedence::Assignment;
@@ -459,7 +878,10 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
             self.advance();
             let infix_rule = self.get_parse_rule(self.previous.unwrap().token_type).infix;
             if let Some(infix_func) = infix_rule {
+                // Everything since `start` is the operator's left operand
+                self.operand_start = Some(start);
                 infix_func(self, can_assign);
+                self.operand_start = None;
             }
         }
 
@@ -468,64 +890,65 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
         }
     }
 
-    fn parse_variable(&mut self, error_message: &str) -> u8 {
+    fn parse_variable(&mut self, error_message: &str) -> usize {
         self.consume(TokenType::Identifier, error_message);
         self.declare_variable();
 
         // We don't look up local variables by name at runtime so we
         // don't need to add the variable's name to the constant table
-        if self.locals.scope_depth > 0 {
+        if self.state.locals.scope_depth > 0 {
             return 0;
         }
 
         self.identifier_constant(&self.previous.unwrap())
     }
 
-    fn identifier_constant(&mut self, name: &Token) -> u8 {
-        let object = Object::Str(LoxString::copy_string(self.vm, name.lexeme));
-        let value = Value::Obj(Box::new(object));
-        self.make_constant(value)
+    fn identifier_constant(&mut self, name: &Token) -> usize {
+        let string = LoxString::copy_string(self.vm, name.lexeme);
+        self.make_constant(Value::Obj(Object::Str(string)))
     }
 
     fn declare_variable(&mut self) {
         // We only declare local variables so exit early if we're in the global scope
-        if self.locals.scope_depth == 0 {
+        if self.state.locals.scope_depth == 0 {
             return;
         }
 
         // We just consumed the identifier so this is safe
         let name = self.previous.unwrap();
 
-        if self.locals.contains_in_current_scope(name) {
+        if self.state.locals.contains_in_current_scope(name) {
             self.error("Already a variable with this name in this scope.");
         }
 
-        if self.locals.add(name).is_err() {
+        if self.state.locals.add(name).is_err() {
             self.error("Too many local variables in function.");
         }
     }
 
-    fn define_variable(&mut self, global: u8) {
+    fn define_variable(&mut self, global: usize) {
         // We don't need to create a local variable at runtime because
         // its value is already on top of the stack.
-        if self.locals.scope_depth > 0 {
-            self.locals.mark_initialized();
+        if self.state.locals.scope_depth > 0 {
+            self.state.locals.mark_initialized();
             return;
         }
 
-        self.emit_opcode(OpCode::DefineGlobal);
-        self.emit_byte(global);
+        self.emit_with_operand(OpCode::DefineGlobal, global);
     }
 
-    fn named_variable(&mut self, name: Token, can_assign: bool) {
-        let arg = self.locals.resolve_local(name);
-        let (arg, get_op, set_op) = match arg {
-            Err(ResolveLocalError::Uninitialized) => {
+    fn named_variable(&mut self, name: Token<'src>, can_assign: bool) {
+        let (arg, get_op, set_op) = match self.resolve_variable(name) {
+            Err(ResolveError::Uninitialized) => {
                 self.error("Can't read local variable in its own initializer.");
                 return;
             }
-            Ok(arg) => (arg, OpCode::GetLocal, OpCode::SetLocal),
-            Err(ResolveLocalError::NotFound) => (
+            Err(ResolveError::TooManyUpvalues) => {
+                self.error("Too many closure variables in function.");
+                return;
+            }
+            Ok((arg, get_op, set_op)) => (arg, get_op, set_op),
+            Err(ResolveError::NotFound) => (
                 self.identifier_constant(&name),
                 OpCode::GetGlobal,
                 OpCode::SetGlobal,
@@ -534,22 +957,51 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
 
         if can_assign && self.match_(TokenType::Equal) {
             self.expression();
-            self.emit_opcode(set_op);
-            self.emit_byte(arg);
+            self.emit_with_operand(set_op, arg);
         } else {
-            self.emit_opcode(get_op);
-            self.emit_byte(arg);
+            self.emit_with_operand(get_op, arg);
+        }
+    }
+
+    // Looks for a local variable in the current function and then for one
+    // captured from an enclosing function.
+    fn resolve_variable(
+        &mut self,
+        name: Token<'src>,
+    ) -> Result<(usize, OpCode, OpCode), ResolveError> {
+        match self.state.locals.resolve_local(name) {
+            Ok(arg) => return Ok((arg as usize, OpCode::GetLocal, OpCode::SetLocal)),
+            Err(ResolveError::NotFound) => {}
+            Err(error) => return Err(error),
+        }
+
+        let arg = self.state.resolve_upvalue(name)?;
+        Ok((arg as usize, OpCode::GetUpvalue, OpCode::SetUpvalue))
+    }
+
+    // Creates a token for an identifier that doesn't appear in the source
+    fn synthetic_token(&self, lexeme: &'static str) -> Token<'src> {
+        Token {
+            token_type: TokenType::Identifier,
+            lexeme,
+            line: self.previous.unwrap().line,
+            column: self.previous.unwrap().column,
+            span: self.previous.unwrap().span,
         }
     }
 
-    fn make_constant(&mut self, value: Value) -> u8 {
+    fn make_constant(&mut self, value: Value) -> usize {
+        // The function we're compiling isn't on the heap yet so the GC can't
+        // see its constants unless we tell it about them
+        self.vm.push_compiler_root(value);
+
         let constant = self.current_chunk_mut().add_constant(value);
-        if constant > u8::MAX as usize {
+        if constant > MAX_LONG_CONSTANT {
             self.error("Too many constants in one chunk.");
             return 0;
         }
 
-        constant as u8
+        constant
     }
 
     fn get_parse_rule(&self, token_type: TokenType) -> ParseRule {
@@ -590,6 +1042,104 @@ struct ParseRule {
 type ParseFn = fn(compiler: &mut Compiler<'_, '_>, can_assign: bool) -> ();
 
 const UINT8_COUNT: usize = u8::MAX as usize + 1;
+const MAX_LONG_CONSTANT: usize = (1 << 24) - 1;
+const LOCALS_MAX: usize = u16::MAX as usize + 1; // slots addressable by `Wide`
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum FunctionType {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
+// Per-function compilation state. Each function declaration pushes a new
+// `FunctionState` that points back at the one for its enclosing function.
+#[derive(Debug)]
+struct FunctionState<'src> {
+    enclosing: Option<Box<FunctionState<'src>>>,
+    function: Function,
+    function_type: FunctionType,
+    locals: Locals<'src>,
+    upvalues: Vec<Upvalue>,
+    last_value: Option<LoadedValue>, // see `Compiler::emit_value`
+}
+
+impl<'src> FunctionState<'src> {
+    fn new(function_type: FunctionType, name: Option<Gc<LoxString>>) -> Self {
+        Self {
+            enclosing: None,
+            function: Function::new(name),
+            function_type,
+            locals: Locals::new(function_type),
+            upvalues: Vec::with_capacity(UINT8_COUNT),
+            last_value: None,
+        }
+    }
+
+    fn resolve_upvalue(&mut self, name: Token<'src>) -> Result<u8, ResolveError> {
+        let enclosing = match self.enclosing.as_mut() {
+            Some(enclosing) => enclosing,
+            None => return Err(ResolveError::NotFound), // global scope
+        };
+
+        // The variable might be a local in the immediately enclosing function...
+        match enclosing.locals.resolve_local(name) {
+            Ok(index) => {
+                enclosing.locals.locals[index as usize].is_captured = true;
+                return self.add_upvalue(index, true);
+            }
+            Err(ResolveError::NotFound) => {}
+            Err(error) => return Err(error),
+        }
+
+        // ...or it might be further out, in which case the enclosing function
+        // has to capture it too so that we can capture it from there.
+        let index = enclosing.resolve_upvalue(name)?;
+        self.add_upvalue(index.into(), false)
+    }
+
+    fn add_upvalue(&mut self, index: u16, is_local: bool) -> Result<u8, ResolveError> {
+        // Closures that reference the same variable multiple times share one upvalue
+        if let Some(existing) = self
+            .upvalues
+            .iter()
+            .position(|upvalue| upvalue.index == index && upvalue.is_local == is_local)
+        {
+            return Ok(existing as u8);
+        }
+
+        if self.upvalues.len() == UINT8_COUNT {
+            return Err(ResolveError::TooManyUpvalues);
+        }
+
+        self.upvalues.push(Upvalue { index, is_local });
+        self.function.upvalue_count = self.upvalues.len();
+        Ok((self.upvalues.len() - 1) as u8)
+    }
+}
+
+// The code that loads a value known at compile time
+#[derive(Debug, Copy, Clone)]
+struct LoadedValue {
+    start: usize,
+    end: usize,
+    constants: usize, // the size of the constant table before it was loaded
+    value: Value,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Upvalue {
+    index: u16,     // local slot or upvalue index in the enclosing function
+    is_local: bool, // whether `index` refers to a local slot
+}
+
+// Tracks the class whose body we're currently compiling (if any)
+#[derive(Debug)]
+struct ClassState {
+    enclosing: Option<Box<ClassState>>,
+    has_superclass: bool,
+}
 
 #[derive(Debug)]
 struct Locals<'src> {
@@ -598,16 +1148,37 @@ struct Locals<'src> {
 }
 
 impl<'src> Locals<'src> {
-    fn new() -> Self {
+    fn new(function_type: FunctionType) -> Self {
+        let mut locals = Vec::with_capacity(UINT8_COUNT);
+
+        // The VM uses slot zero for the function being called so we claim it
+        // here. In methods it holds the receiver and can be referenced as
+        // `this`. Otherwise we give it a name that can never be referenced.
+        let lexeme = match function_type {
+            FunctionType::Initializer | FunctionType::Method => "this",
+            FunctionType::Function | FunctionType::Script => "",
+        };
+        locals.push(Local {
+            name: Token {
+                token_type: TokenType::Identifier,
+                lexeme,
+                line: 0,
+                column: 0,
+                span: Span::default(),
+            },
+            depth: Some(0),
+            is_captured: false,
+        });
+
         Self {
-            locals: Vec::with_capacity(UINT8_COUNT),
+            locals,
             scope_depth: 0,
         }
     }
 
     fn add(&mut self, name: Token<'src>) -> Result<(), ()> {
         // Check if we already have the maximum number of local variables
-        if self.locals.len() == UINT8_COUNT {
+        if self.locals.len() == LOCALS_MAX {
             return Err(());
         }
 
@@ -618,7 +1189,7 @@ impl<'src> Locals<'src> {
 
     fn contains_in_current_scope(&self, name: Token<'src>) -> bool {
         for local in self.locals.iter().rev() {
-            if local.depth.map_or(false, |depth| depth < self.scope_depth) {
+            if local.depth.is_some_and(|depth| depth < self.scope_depth) {
                 break;
             }
 
@@ -630,41 +1201,52 @@ impl<'src> Locals<'src> {
         false
     }
 
-    fn resolve_local(&self, name: Token<'src>) -> Result<u8, ResolveLocalError> {
+    fn resolve_local(&self, name: Token<'src>) -> Result<u16, ResolveError> {
         for (i, local) in self.locals.iter().enumerate().rev() {
             if local.name.identifiers_equal(&name) {
                 if local.depth.is_none() {
                     // We're trying to resolve a variable before it's initialized
                     // (e.g., inside its own initializer).
-                    return Err(ResolveLocalError::Uninitialized);
+                    return Err(ResolveError::Uninitialized);
                 } else {
-                    return Ok(i as u8);
+                    return Ok(i as u16);
                 }
             }
         }
 
-        Err(ResolveLocalError::NotFound)
+        Err(ResolveError::NotFound)
     }
 
     fn mark_initialized(&mut self) {
+        // Global functions aren't stored as locals
+        if self.scope_depth == 0 {
+            return;
+        }
+
         self.locals.last_mut().unwrap().depth = Some(self.scope_depth);
     }
 }
 
-enum ResolveLocalError {
+enum ResolveError {
     Uninitialized,
     NotFound,
+    TooManyUpvalues,
 }
 
 #[derive(Debug)]
 struct Local<'src> {
     name: Token<'src>,
     depth: Option<usize>,
+    is_captured: bool, // whether a closure references this variable
 }
 
 impl<'src> Local<'src> {
     fn new(name: Token<'src>) -> Self {
-        Self { name, depth: None }
+        Self {
+            name,
+            depth: None,
+            is_captured: false,
+        }
     }
 }
 
@@ -673,50 +1255,128 @@ fn grouping(compiler: &mut Compiler, _can_assign: bool) {
     compiler.consume(TokenType::RightParen, "Expect ')' after expression.");
 }
 
+fn call(compiler: &mut Compiler, _can_assign: bool) {
+    let arg_count = compiler.argument_list();
+    compiler.emit_opcode(OpCode::Call);
+    compiler.emit_byte(arg_count);
+}
+
+fn dot(compiler: &mut Compiler, can_assign: bool) {
+    compiler.consume(TokenType::Identifier, "Expect property name after '.'.");
+    let name = compiler.identifier_constant(&compiler.previous.unwrap());
+
+    if can_assign && compiler.match_(TokenType::Equal) {
+        compiler.expression();
+        compiler.emit_with_operand(OpCode::SetProperty, name);
+    } else if compiler.match_(TokenType::LeftParen) {
+        // Calling a method right away lets us skip creating a bound method
+        let arg_count = compiler.argument_list();
+        compiler.emit_with_operand(OpCode::Invoke, name);
+        compiler.emit_byte(arg_count);
+    } else {
+        compiler.emit_with_operand(OpCode::GetProperty, name);
+    }
+}
+
 fn binary(compiler: &mut Compiler, _can_assign: bool) {
-    let operator_type = compiler.previous.unwrap().token_type;
-    let parse_rule = compiler.get_parse_rule(operator_type);
+    let operator = compiler.previous.unwrap();
+    let left = compiler
+        .operand_start
+        .take()
+        .and_then(|start| compiler.value_since(start));
+
+    let right_start = compiler.current_chunk().count();
+    let parse_rule = compiler.get_parse_rule(operator.token_type);
     let precedence = parse_rule.precedence.higher();
     compiler.parse_precedence(precedence);
 
-    match operator_type {
-        TokenType::Plus => compiler.emit_opcode(OpCode::Add),
-        TokenType::Minus => compiler.emit_opcode(OpCode::Subtract),
-        TokenType::Star => compiler.emit_opcode(OpCode::Multiply),
-        TokenType::Slash => compiler.emit_opcode(OpCode::Divide),
-        TokenType::BangEqual => compiler.emit_opcodes(OpCode::Equal, OpCode::Not),
-        TokenType::EqualEqual => compiler.emit_opcode(OpCode::Equal),
-        TokenType::Greater => compiler.emit_opcode(OpCode::Greater),
-        TokenType::GreaterEqual => compiler.emit_opcodes(OpCode::Less, OpCode::Not),
-        TokenType::Less => compiler.emit_opcode(OpCode::Less),
-        TokenType::LessEqual => compiler.emit_opcodes(OpCode::Greater, OpCode::Not),
+    let (opcode, negate) = match operator.token_type {
+        TokenType::Plus => (OpCode::Add, false),
+        TokenType::Minus => (OpCode::Subtract, false),
+        TokenType::Star => (OpCode::Multiply, false),
+        TokenType::Slash => (OpCode::Divide, false),
+        TokenType::BangEqual => (OpCode::Equal, true),
+        TokenType::EqualEqual => (OpCode::Equal, false),
+        TokenType::Greater => (OpCode::Greater, false),
+        TokenType::GreaterEqual => (OpCode::Less, true),
+        TokenType::Less => (OpCode::Less, false),
+        TokenType::LessEqual => (OpCode::Greater, true),
         _ => unreachable!(),
+    };
+
+    // e.g. `60 * 60` is compiled to the constant 3600
+    if let (Some(left), Some(right)) = (left, compiler.value_since(right_start)) {
+        if let Some(value) = fold_binary(compiler.vm, opcode, left.value, right.value) {
+            compiler.fold(left, if negate { !&value } else { value });
+            return;
+        }
+    }
+
+    compiler.emit_opcode_at(opcode, operator);
+    if negate {
+        compiler.emit_opcode_at(OpCode::Not, operator);
     }
 }
 
 fn unary(compiler: &mut Compiler, _can_assign: bool) {
-    let operator_type = compiler.previous.unwrap().token_type;
+    let operator = compiler.previous.unwrap();
 
     // Compile the expression
+    let start = compiler.current_chunk().count();
     compiler.parse_precedence(Precedence::Unary);
 
-    match operator_type {
-        TokenType::Minus => compiler.emit_opcode(OpCode::Negate),
-        TokenType::Bang => compiler.emit_opcode(OpCode::Not),
+    if let Some(operand) = compiler.value_since(start) {
+        let value = match (operator.token_type, operand.value) {
+            (TokenType::Minus, Value::Number(value)) => Some(Value::Number(-value)),
+            (TokenType::Bang, value) => Some(!&value),
+            _ => None,
+        };
+
+        if let Some(value) = value {
+            compiler.fold(operand, value);
+            return;
+        }
+    }
+
+    match operator.token_type {
+        TokenType::Minus => compiler.emit_opcode_at(OpCode::Negate, operator),
+        TokenType::Bang => compiler.emit_opcode_at(OpCode::Not, operator),
         _ => {}
     }
 }
 
+// The result of applying a binary operator to two values known at compile
+// time, unless it would be a runtime error
+fn fold_binary(vm: &mut Vm, opcode: OpCode, a: Value, b: Value) -> Option<Value> {
+    use Value::Number;
+
+    match (opcode, a, b) {
+        (OpCode::Add, Number(a), Number(b)) => Some(Number(a + b)),
+        (OpCode::Subtract, Number(a), Number(b)) => Some(Number(a - b)),
+        (OpCode::Multiply, Number(a), Number(b)) => Some(Number(a * b)),
+        (OpCode::Divide, Number(a), Number(b)) => Some(Number(a / b)),
+        (OpCode::Add, Value::Obj(Object::Str(a)), Value::Obj(Object::Str(b))) => {
+            // Both strings are compiler roots so can't be collected here
+            Some(Value::Obj(Object::Str(LoxString::add(vm, &a, &b))))
+        }
+        (OpCode::Equal, a, b) => Some(Value::Bool(a == b)),
+        // Like the VM, NaN can't be compared
+        (OpCode::Greater, a, b) => a.partial_cmp(&b).map(|o| Value::Bool(o.is_gt())),
+        (OpCode::Less, a, b) => a.partial_cmp(&b).map(|o| Value::Bool(o.is_lt())),
+        _ => None,
+    }
+}
+
 fn number(compiler: &mut Compiler, _can_assign: bool) {
     let value: f64 = compiler.previous.unwrap().lexeme.parse().unwrap();
-    compiler.emit_constant(Value::Number(value));
+    compiler.emit_value(Value::Number(value));
 }
 
 fn literal(compiler: &mut Compiler, _can_assign: bool) {
     match compiler.previous.unwrap().token_type {
-        TokenType::False => compiler.emit_opcode(OpCode::False),
-        TokenType::Nil => compiler.emit_opcode(OpCode::Nil),
-        TokenType::True => compiler.emit_opcode(OpCode::True),
+        TokenType::False => compiler.emit_value(Value::Bool(false)),
+        TokenType::Nil => compiler.emit_value(Value::Nil),
+        TokenType::True => compiler.emit_value(Value::Bool(true)),
         _ => unreachable!(),
     }
 }
@@ -724,16 +1384,51 @@ fn literal(compiler: &mut Compiler, _can_assign: bool) {
 fn string(compiler: &mut Compiler, _can_assign: bool) {
     let lexeme = compiler.previous.unwrap().lexeme;
     let lexeme = &lexeme[1..lexeme.len() - 1];
-    let object = Object::Str(LoxString::copy_string(compiler.vm, lexeme));
-    let value = Value::Obj(Box::new(object));
+    let string = LoxString::copy_string(compiler.vm, lexeme);
 
-    compiler.emit_constant(value);
+    compiler.emit_value(Value::Obj(Object::Str(string)));
 }
 
 fn variable(compiler: &mut Compiler, can_assign: bool) {
     compiler.named_variable(compiler.previous.unwrap(), can_assign);
 }
 
+fn this(compiler: &mut Compiler, _can_assign: bool) {
+    if compiler.class_state.is_none() {
+        compiler.error("Can't use 'this' outside of a class.");
+        return;
+    }
+
+    // `this` is just a local variable that can't be assigned to
+    variable(compiler, false);
+}
+
+fn super_(compiler: &mut Compiler, _can_assign: bool) {
+    match &compiler.class_state {
+        None => compiler.error("Can't use 'super' outside of a class."),
+        Some(class_state) if !class_state.has_superclass => {
+            compiler.error("Can't use 'super' in a class with no superclass.")
+        }
+        Some(_) => {}
+    }
+
+    compiler.consume(TokenType::Dot, "Expect '.' after 'super'.");
+    compiler.consume(TokenType::Identifier, "Expect superclass method name.");
+    let name = compiler.identifier_constant(&compiler.previous.unwrap());
+
+    // Look up the method on the superclass but bind it to the current instance
+    compiler.named_variable(compiler.synthetic_token("this"), false);
+    if compiler.match_(TokenType::LeftParen) {
+        let arg_count = compiler.argument_list();
+        compiler.named_variable(compiler.synthetic_token("super"), false);
+        compiler.emit_with_operand(OpCode::SuperInvoke, name);
+        compiler.emit_byte(arg_count);
+    } else {
+        compiler.named_variable(compiler.synthetic_token("super"), false);
+        compiler.emit_with_operand(OpCode::GetSuper, name);
+    }
+}
+
 fn and(compiler: &mut Compiler, _can_assign: bool) {
     // We've evaluated the left-hand side of the And operator and its value is on the stack.
     // If it's false, we can skip evaluating the right-hand side.
@@ -770,8 +1465,8 @@ static RULE_TABLE: [ParseRule; 40] = [
     // LeftParen
     ParseRule {
         prefix: Some(grouping),
-        infix: None,
-        precedence: Precedence::None,
+        infix: Some(call),
+        precedence: Precedence::Call,
     },
     // RightParen
     ParseRule {
@@ -800,8 +1495,8 @@ static RULE_TABLE: [ParseRule; 40] = [
     // Dot
     ParseRule {
         prefix: None,
-        infix: None,
-        precedence: Precedence::None,
+        infix: Some(dot),
+        precedence: Precedence::Call,
     },
     // Minus
     ParseRule {
@@ -841,7 +1536,7 @@ static RULE_TABLE: [ParseRule; 40] = [
     },
     // BangEqual
     ParseRule {
-        prefix: Some(binary),
+        prefix: None,
         infix: Some(binary),
         precedence: Precedence::Equality,
     },
@@ -853,31 +1548,31 @@ static RULE_TABLE: [ParseRule; 40] = [
     },
     // EqualEqual
     ParseRule {
-        prefix: Some(binary),
+        prefix: None,
         infix: Some(binary),
         precedence: Precedence::Equality,
     },
     // Greater
     ParseRule {
-        prefix: Some(binary),
+        prefix: None,
         infix: Some(binary),
         precedence: Precedence::Comparison,
     },
     // GreaterEqual
     ParseRule {
-        prefix: Some(binary),
+        prefix: None,
         infix: Some(binary),
         precedence: Precedence::Comparison,
     },
     // Less
     ParseRule {
-        prefix: Some(binary),
+        prefix: None,
         infix: Some(binary),
         precedence: Precedence::Comparison,
     },
     // LessEqual
     ParseRule {
-        prefix: Some(binary),
+        prefix: None,
         infix: Some(binary),
         precedence: Precedence::Comparison,
     },
@@ -967,13 +1662,13 @@ static RULE_TABLE: [ParseRule; 40] = [
     },
     // Super
     ParseRule {
-        prefix: None,
+        prefix: Some(super_),
         infix: None,
         precedence: Precedence::None,
     },
     // This
     ParseRule {
-        prefix: None,
+        prefix: Some(this),
         infix: None,
         precedence: Precedence::None,
     },
@@ -1008,3 +1703,104 @@ static RULE_TABLE: [ParseRule; 40] = [
         precedence: Precedence::None,
     },
 ];
+
+mod tests {
+    #[allow(unused)]
+    use super::*;
+
+    #[allow(unused)]
+    fn opcodes(source: &str) -> Vec<String> {
+        let mut vm = Vm::new();
+        let script = Compiler::compile(&mut vm, source).unwrap();
+        script.chunk.instructions().map(|i| i.name()).collect()
+    }
+
+    #[test]
+    fn folds_constant_expressions() {
+        let mut vm = Vm::new();
+        let source = "var x = 60 * 60 * 24;\nprint -(2 + 3) * x;\nprint \"a\" + \"b\" == \"ab\";\nprint !(1 <= 2);";
+        let script = Compiler::compile(&mut vm, source).unwrap();
+
+        assert_eq!(
+            "== <script> ==\n\
+             0000    1 OP_CONSTANT         1 86400\n\
+             0002    | OP_DEFINE_GLOBAL    0 \"x\"\n\
+             0004    2 OP_CONSTANT         2 -5\n\
+             0006    | OP_GET_GLOBAL       3 \"x\"\n\
+             0008    | OP_MULTIPLY\n\
+             0009    | OP_PRINT\n\
+             0010    3 OP_TRUE\n\
+             0011    | OP_PRINT\n\
+             0012    4 OP_FALSE\n\
+             0013    | OP_PRINT\n\
+             0014    | OP_NIL\n\
+             0015    | OP_RETURN\n",
+            script.disassembly()
+        );
+
+        // The operands don't take up room in the constant table
+        assert_eq!(4, script.chunk.constants.len());
+    }
+
+    #[test]
+    fn leaves_anything_else_to_runtime() {
+        // Variables, runtime errors and NaN comparisons (also a runtime error)
+        assert_eq!(
+            vec![
+                "OP_GET_GLOBAL",
+                "OP_CONSTANT",
+                "OP_ADD",
+                "OP_CONSTANT",
+                "OP_ADD",
+                "OP_PRINT"
+            ],
+            opcodes("print x + 1 + 2;")[..6]
+        );
+        assert_eq!(
+            vec!["OP_CONSTANT", "OP_CONSTANT", "OP_ADD", "OP_PRINT"],
+            opcodes("print \"a\" + 1;")[..4]
+        );
+        assert_eq!(
+            vec!["OP_CONSTANT", "OP_NEGATE", "OP_PRINT"],
+            opcodes("print -\"a\";")[..3]
+        );
+        assert_eq!(
+            vec![
+                "OP_CONSTANT",
+                "OP_CONSTANT",
+                "OP_LESS",
+                "OP_NOT",
+                "OP_PRINT"
+            ],
+            opcodes("print (0 / 0) >= 1;")[..5]
+        );
+
+        // Only the 1 is constant, not the whole left operand
+        assert_eq!(
+            vec![
+                "OP_TRUE",
+                "OP_JUMP_IF_FALSE",
+                "OP_POP",
+                "OP_CONSTANT",
+                "OP_CONSTANT",
+                "OP_ADD"
+            ],
+            opcodes("print (true and 1) + 2;")[..6]
+        );
+    }
+
+    #[test]
+    fn comparisons_need_a_left_operand() {
+        for operator in ["==", "!=", ">", ">=", "<", "<="] {
+            let mut vm = Vm::new();
+            let source = format!("print {operator} 1;");
+            let Err(VmError::CompileError(errors)) = Compiler::compile(&mut vm, &source) else {
+                panic!("expected a compile error for {operator}");
+            };
+
+            assert_eq!(1, errors.len());
+            assert_eq!(operator, errors[0].lexeme);
+            assert_eq!("Expect expression.", errors[0].message);
+        }
+    }
+}
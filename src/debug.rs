@@ -1,77 +1,573 @@
-#[allow(unused)]
-use crate::chunk::{Chunk, OpCode};
-use crate::value;
+use std::fmt::{Display, Write};
+
+use crate::chunk::{Chunk, OpCode, CAPTURE_LOCAL, CAPTURE_WIDE};
+use crate::function::Function;
+use crate::gc::Root;
+use crate::object::Object;
+use crate::value::Value;
 use OpCode::*;
 
-impl Chunk {
-    pub fn disassemble(&self, name: &str) {
-        println!("== {name} ==");
+// One decoded instruction, see `Chunk::instructions`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    pub offset: usize,
+    pub line: usize,
+    pub opcode: OpCode,
+    pub wide: bool, // has an `OP_WIDE` prefix
+
+    // As encoded, e.g. the constant index and then the argument count for
+    // `OP_INVOKE`
+    pub operands: Vec<usize>,
+
+    pub(crate) constant: Option<Value>, // what the constant index refers to
+    pub jump_target: Option<usize>,     // where a jump or loop goes to
+    pub captures: Vec<Capture>,         // the variables an `OP_CLOSURE` captures
+    pub len: usize,                     // in bytes, including the prefix and operands
+}
+
+// A variable captured by a closure, from the enclosing function's locals or
+// its own upvalues
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capture {
+    pub is_local: bool,
+    pub index: usize,
+    pub wide: bool, // whether the index takes two bytes instead of one
+}
 
-        let mut offset = 0;
-        while offset < self.code.len() {
-            offset = self.disassemble_instruction(offset);
+impl Capture {
+    // In bytes, including the flags
+    pub fn width(&self) -> usize {
+        if self.wide {
+            3
+        } else {
+            2
         }
     }
+}
 
-    pub fn disassemble_instruction(&self, offset: usize) -> usize {
-        print!("{:04} ", offset);
+// Decodes a chunk one instruction at a time. The bytecode has to be valid,
+// e.g. as checked by `Function::verify`.
+#[derive(Debug, Clone)]
+pub struct Instructions<'a> {
+    chunk: &'a Chunk,
+    offset: usize,
+}
 
-        if offset > 0 && self.lines[offset] == self.lines[offset - 1] {
-            print!("   | ");
-        } else {
-            print!("{:4} ", self.lines[offset]);
+impl Iterator for Instructions<'_> {
+    type Item = Instruction;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.chunk.code.len() {
+            return None;
+        }
+
+        let instruction = self.chunk.instruction_at(self.offset);
+        self.offset += instruction.len;
+        Some(instruction)
+    }
+}
+
+impl Chunk {
+    pub fn instructions(&self) -> Instructions<'_> {
+        Instructions {
+            chunk: self,
+            offset: 0,
+        }
+    }
+
+    pub fn instruction_at(&self, offset: usize) -> Instruction {
+        let mut opcode: OpCode = self.code[offset].try_into().unwrap();
+        let wide = opcode == Wide;
+        if wide {
+            opcode = self.code[offset + 1].try_into().unwrap();
         }
 
-        let instruction: &OpCode = &self.code[offset].try_into().unwrap();
-        match instruction {
-            Constant | DefineGlobal | GetGlobal | SetGlobal => {
-                self.constant_instruction(instruction.name(), offset)
+        // Operands start after the opcode (and the prefix)
+        let start = offset + 1 + wide as usize;
+        let operand = |position: usize, width: usize| {
+            self.code[start + position..start + position + width]
+                .iter()
+                .fold(0, |operand, &byte| (operand << 8) | byte as usize)
+        };
+
+        let mut instruction = Instruction {
+            offset,
+            line: self.line_at(offset),
+            opcode,
+            wide,
+            operands: Vec::new(),
+            constant: None,
+            jump_target: None,
+            captures: Vec::new(),
+            len: 0,
+        };
+
+        match opcode {
+            Constant | DefineGlobal | GetGlobal | SetGlobal | Class | GetProperty | SetProperty
+            | Method | GetSuper | Closure => instruction.operands.push(operand(0, 1)),
+            ConstantLong | DefineGlobalLong | GetGlobalLong | SetGlobalLong | ClassLong
+            | GetPropertyLong | SetPropertyLong | MethodLong | GetSuperLong | ClosureLong => {
+                instruction.operands.push(operand(0, 3))
+            }
+            Invoke | SuperInvoke => instruction.operands = vec![operand(0, 1), operand(1, 1)],
+            InvokeLong | SuperInvokeLong => {
+                instruction.operands = vec![operand(0, 3), operand(3, 1)]
+            }
+            GetLocal | SetLocal if wide => instruction.operands.push(operand(0, 2)),
+            GetLocal | SetLocal | GetUpvalue | SetUpvalue | Call => {
+                instruction.operands.push(operand(0, 1))
+            }
+            Jump | JumpIfFalse | Loop => {
+                let width = if wide { 4 } else { 2 };
+                let distance = operand(0, width);
+                let next = start + width;
+                instruction.operands.push(distance);
+                instruction.jump_target = Some(match opcode {
+                    Loop => next - distance,
+                    _ => next + distance,
+                });
             }
             Return | Less | Greater | Equal | Not | False | True | Nil | Divide | Multiply
-            | Subtract | Add | Negate | Print | Pop => {
-                self.simple_instruction(instruction.name(), offset)
+            | Subtract | Add | Negate | Print | Pop | CloseUpvalue | Inherit => {}
+            Wide => unreachable!("OP_WIDE can't prefix itself"),
+        }
+
+        if let Some(constant) = instruction.constant_index() {
+            instruction.constant = Some(self.constants[constant]);
+        }
+
+        let mut len = start - offset + instruction.operand_width();
+        if let Some(Value::Obj(Object::Function(function))) = instruction.constant {
+            // Each captured variable is encoded as a flags byte and an index
+            // following the constant
+            for _ in 0..function.upvalue_count {
+                let flags = self.code[offset + len];
+                let wide = flags & CAPTURE_WIDE != 0;
+                let index = if wide {
+                    u16::from_be_bytes([self.code[offset + len + 1], self.code[offset + len + 2]])
+                } else {
+                    self.code[offset + len + 1].into()
+                };
+                let capture = Capture {
+                    is_local: flags & CAPTURE_LOCAL != 0,
+                    index: index.into(),
+                    wide,
+                };
+                len += capture.width();
+                instruction.captures.push(capture);
             }
-            GetLocal | SetLocal => self.byte_instruction(instruction.name(), offset),
-            Jump => self.jump_instruction(instruction.name(), 1, offset),
-            JumpIfFalse => self.jump_instruction(instruction.name(), 1, offset),
-            Loop => self.jump_instruction(instruction.name(), -1, offset),
         }
+
+        instruction.len = len;
+        instruction
+    }
+
+    // The listing printed by `disassemble`
+    pub fn disassembly(&self, name: &str) -> String {
+        let mut listing = format!("== {name} ==\n");
+        for instruction in self.instructions() {
+            self.write_instruction(&mut listing, &instruction);
+        }
+        listing
     }
 
-    fn simple_instruction(&self, name: &str, offset: usize) -> usize {
-        println!("{name}");
-        offset + 1
+    pub fn disassemble(&self, name: &str, out: &mut dyn std::io::Write) -> std::io::Result<()> {
+        out.write_all(self.disassembly(name).as_bytes())
     }
 
-    fn constant_instruction(&self, name: &str, offset: usize) -> usize {
-        let constant = self.code[offset + 1];
+    // Writes the listing of one instruction to `out`, returning the offset of
+    // the next instruction
+    pub fn disassemble_instruction(
+        &self,
+        offset: usize,
+        out: &mut dyn std::io::Write,
+    ) -> std::io::Result<usize> {
+        let instruction = self.instruction_at(offset);
+        let mut line = String::new();
+        self.write_instruction(&mut line, &instruction);
+        out.write_all(line.as_bytes())?;
 
-        print!("{:-16} {:4} ", name, constant);
-        value::print_value(&self.constants[constant as usize]);
-        println!();
+        Ok(offset + instruction.len)
+    }
+
+    fn write_instruction(&self, out: &mut String, instruction: &Instruction) {
+        let offset = instruction.offset;
+        write!(out, "{:04} ", offset).unwrap();
 
-        offset + 2
+        // Only show the line when it changes
+        if offset > 0 && instruction.line == self.line_at(offset - 1) {
+            write!(out, "   | ").unwrap();
+        } else {
+            write!(out, "{:4} ", instruction.line).unwrap();
+        }
+
+        writeln!(out, "{instruction}").unwrap();
     }
 
-    fn byte_instruction(&self, name: &str, offset: usize) -> usize {
-        let slot = self.code[offset + 1];
-        println!("{:-16} {:4} ", name, slot);
-        offset + 2
+    // The same as `disassembly` but as JSON, e.g.
+    //
+    //   {"name": "<script>", "instructions": [{"offset": 0, ...}, ...]}
+    pub fn disassembly_json(&self, name: &str) -> String {
+        let instructions: Vec<_> = self.instructions().map(|i| i.to_json()).collect();
+        format!(
+            "{{\"name\": {}, \"instructions\": [{}]}}",
+            json_string(name),
+            instructions.join(", ")
+        )
     }
+}
+
+impl Instruction {
+    // The name of the opcode, e.g. "OP_WIDE OP_JUMP"
+    pub fn name(&self) -> String {
+        match self.wide {
+            true => format!("{} {}", Wide.name(), self.opcode.name()),
+            false => self.opcode.name().to_string(),
+        }
+    }
+
+    fn constant_index(&self) -> Option<usize> {
+        match self.opcode {
+            Constant | DefineGlobal | GetGlobal | SetGlobal | Class | GetProperty | SetProperty
+            | Method | GetSuper | Closure | Invoke | SuperInvoke => Some(self.operands[0]),
+            _ if self.opcode.short_form().is_some() => Some(self.operands[0]),
+            _ => None,
+        }
+    }
+
+    // The total size of the operands, not counting captured variables
+    fn operand_width(&self) -> usize {
+        match self.opcode {
+            InvokeLong | SuperInvokeLong => 4,
+            _ if self.opcode.short_form().is_some() => 3,
+            Jump | JumpIfFalse | Loop if self.wide => 4,
+            GetLocal | SetLocal if self.wide => 2,
+            Jump | JumpIfFalse | Loop | Invoke | SuperInvoke => 2,
+            _ if self.operands.is_empty() => 0,
+            _ => 1,
+        }
+    }
+
+    // e.g. {"offset": 2, "line": 1, "opcode": "OP_CONSTANT", "wide": false,
+    // "operands": [0], "constant": {"type": "number", "value": 1.5},
+    // "jump_target": null, "captures": []}
+    pub fn to_json(&self) -> String {
+        let operands: Vec<_> = self.operands.iter().map(usize::to_string).collect();
+        let constant = self
+            .constant
+            .as_ref()
+            .map_or("null".to_string(), constant_json);
+        let jump_target = self
+            .jump_target
+            .map_or("null".to_string(), |t| t.to_string());
+        let captures: Vec<_> = self
+            .captures
+            .iter()
+            .map(|c| format!("{{\"local\": {}, \"index\": {}}}", c.is_local, c.index))
+            .collect();
+
+        format!(
+            "{{\"offset\": {}, \"line\": {}, \"opcode\": {}, \"wide\": {}, \"operands\": [{}], \
+             \"constant\": {}, \"jump_target\": {}, \"captures\": [{}]}}",
+            self.offset,
+            self.line,
+            json_string(self.opcode.name()),
+            self.wide,
+            operands.join(", "),
+            constant,
+            jump_target,
+            captures.join(", ")
+        )
+    }
+}
+
+// The part of a listing after the offset and line, e.g. `OP_CONSTANT 0 1.5`.
+// An `OP_CLOSURE` is followed by a line for each variable it captures.
+impl Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = self.name();
 
-    fn jump_instruction(&self, name: &str, sign: i16, offset: usize) -> usize {
-        let top = self.code[offset + 1] as u16;
-        let bottom = self.code[offset + 2] as u16;
-        let jump = (top << 8) | bottom;
+        match (self.opcode, &self.constant, self.jump_target) {
+            (Invoke | SuperInvoke | InvokeLong | SuperInvokeLong, Some(constant), _) => write!(
+                f,
+                "{:-16} ({} args) {:4} {}",
+                name, self.operands[1], self.operands[0], constant
+            ),
+            (_, Some(constant), _) => write!(f, "{:-16} {:4} {}", name, self.operands[0], constant),
+            (_, None, Some(target)) => write!(f, "{:-16} {:4} -> {}", name, self.offset, target),
+            _ if self.operands.is_empty() => write!(f, "{name}"),
+            _ => write!(f, "{:-16} {:4} ", name, self.operands[0]),
+        }?;
 
-        let destination = (3 + sign * jump as i16) as isize;
-        println!(
-            "{:-16} {:4} -> {}",
+        let captures: usize = self.captures.iter().map(Capture::width).sum();
+        let mut offset = self.offset + self.len - captures;
+        for capture in &self.captures {
+            let kind = if capture.is_local { "local" } else { "upvalue" };
+            write!(
+                f,
+                "\n{:04}    |                     {} {}",
+                offset, kind, capture.index
+            )?;
+            offset += capture.width();
+        }
+
+        Ok(())
+    }
+}
+
+impl Function {
+    // Like `Chunk::disassembly` but the header also has the arity and the
+    // number of upvalues, if there are any, so that the listing has everything
+    // the assembler needs, e.g. "== <fn add> (arity 2) =="
+    pub fn disassembly(&self) -> String {
+        let mut details = Vec::new();
+        if self.arity > 0 {
+            details.push(format!("arity {}", self.arity));
+        }
+        if self.upvalue_count > 0 {
+            details.push(format!("upvalues {}", self.upvalue_count));
+        }
+
+        let header = if details.is_empty() {
+            self.to_string()
+        } else {
+            format!("{self} ({})", details.join(", "))
+        };
+        self.chunk.disassembly(&header)
+    }
+
+    pub fn disassemble(&self, out: &mut dyn std::io::Write) -> std::io::Result<()> {
+        out.write_all(self.disassembly().as_bytes())
+    }
+
+    // The listings of this function and every function declared inside it, in
+    // the order that `--print-code` prints them: each function after the ones
+    // declared inside it, followed by a blank line
+    pub fn nested_disassembly(&self) -> String {
+        let mut listings = String::new();
+        for constant in &self.chunk.constants {
+            if let Value::Obj(Object::Function(function)) = constant {
+                listings += &function.nested_disassembly();
+            }
+        }
+
+        listings + &self.disassembly() + "\n"
+    }
+
+    // Like `Chunk::disassembly_json` but with the arity, the number of
+    // upvalues, and the functions declared inside this one
+    pub fn disassembly_json(&self) -> String {
+        let name = self
+            .name
+            .map_or("null".to_string(), |name| json_string(name.as_str()));
+        let instructions: Vec<_> = self.chunk.instructions().map(|i| i.to_json()).collect();
+        let functions: Vec<_> = self
+            .chunk
+            .constants
+            .iter()
+            .filter_map(|constant| match constant {
+                Value::Obj(Object::Function(function)) => Some(function.disassembly_json()),
+                _ => None,
+            })
+            .collect();
+
+        format!(
+            "{{\"name\": {}, \"arity\": {}, \"upvalues\": {}, \"instructions\": [{}], \
+             \"functions\": [{}]}}",
             name,
-            offset,
-            offset as isize + destination
+            self.arity,
+            self.upvalue_count,
+            instructions.join(", "),
+            functions.join(", ")
+        )
+    }
+}
+
+fn constant_json(constant: &Value) -> String {
+    let (kind, value) = match constant {
+        Value::Nil => ("nil", "null".to_string()),
+        Value::Bool(b) => ("bool", b.to_string()),
+        // JSON doesn't have NaN or infinity
+        Value::Number(number) if number.is_finite() => ("number", number.to_string()),
+        Value::Number(number) => ("number", json_string(&number.to_string())),
+        Value::Obj(Object::Str(string)) => ("string", json_string(string.as_str())),
+        Value::Obj(Object::Function(function)) => (
+            "function",
+            function
+                .name
+                .map_or("null".to_string(), |name| json_string(name.as_str())),
+        ),
+        Value::Obj(object) => ("object", json_string(&object.to_string())),
+    };
+
+    format!("{{\"type\": \"{kind}\", \"value\": {value}}}")
+}
+
+fn json_string(string: &str) -> String {
+    let mut json = String::with_capacity(string.len() + 2);
+    json.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => write!(json, "\\u{:04x}", c as u32).unwrap(),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+impl Root<Value> {
+    // The listing of a closure's function, or of each of a class's methods,
+    // e.g. for the REPL's `:dis`. Other values have no bytecode of their own.
+    pub fn disassembly(&self) -> Option<String> {
+        match self.value() {
+            Value::Obj(Object::Closure(closure)) => Some(closure.function.disassembly()),
+            Value::Obj(Object::Class(class)) => {
+                let methods = class.methods.borrow();
+                let mut methods: Vec<_> = methods.values().collect();
+                methods.sort_by_key(|method| method.function.display_name());
+
+                let listings = methods
+                    .iter()
+                    .map(|method| method.function.disassembly() + "\n");
+                Some(listings.collect())
+            }
+            _ => None,
+        }
+    }
+}
+
+mod tests {
+    #[allow(unused)]
+    use super::*;
+    #[allow(unused)]
+    use crate::{Assembler, Compiler, Vm};
+
+    #[test]
+    fn decodes_instructions() {
+        let mut vm = Vm::new();
+        let script = Compiler::compile(&mut vm, "var x = 1;\nwhile (x) x = nil;").unwrap();
+        let instructions: Vec<_> = script.chunk.instructions().collect();
+
+        let names: Vec<_> = instructions.iter().map(Instruction::name).collect();
+        assert_eq!(
+            vec![
+                "OP_CONSTANT",
+                "OP_DEFINE_GLOBAL",
+                "OP_GET_GLOBAL",
+                "OP_JUMP_IF_FALSE",
+                "OP_POP",
+                "OP_NIL",
+                "OP_SET_GLOBAL",
+                "OP_POP",
+                "OP_LOOP",
+                "OP_POP",
+                "OP_NIL",
+                "OP_RETURN",
+            ],
+            names
         );
 
-        offset + 3
+        let jump = &instructions[3];
+        assert_eq!((6, 2), (jump.offset, jump.line));
+        assert_eq!(Some(17), jump.jump_target);
+        assert_eq!(Some(4), instructions[8].jump_target);
+        assert_eq!(Some(Value::Number(1.0)), instructions[0].constant);
+    }
+
+    #[test]
+    fn formats_listings() {
+        let mut vm = Vm::new();
+        let script = Compiler::compile(&mut vm, "fun f(a) {\n  return a;\n}").unwrap();
+        let f = script.chunk.constants[1].as_object().as_function();
+
+        assert_eq!(
+            "== <fn f> (arity 1) ==\n\
+             0000    2 OP_GET_LOCAL        1 \n\
+             0002    | OP_RETURN\n\
+             0003    3 OP_NIL\n\
+             0004    | OP_RETURN\n",
+            f.disassembly()
+        );
+        assert_eq!(
+            "{\"offset\": 0, \"line\": 3, \"opcode\": \"OP_CLOSURE\", \"wide\": false, \
+             \"operands\": [1], \"constant\": {\"type\": \"function\", \"value\": \"f\"}, \
+             \"jump_target\": null, \"captures\": []}",
+            script.chunk.instruction_at(0).to_json()
+        );
+    }
+
+    #[test]
+    fn listings_round_trip_through_the_assembler() {
+        let mut vm = Vm::new();
+        let source = "
+            class A { init(n) { this.n = n; } get() { return this.n; } }
+            class B < A { get() { return super.get() + 1; } }
+            fun counter() {
+                var count = 0;
+                fun next() { count = count + 1; return count; }
+                return next;
+            }
+            var c = counter();
+            for (var i = 0; i < 3; i = i + 1) print B(i).get() + c();
+        ";
+        let compiled = Compiler::compile(&mut vm, source).unwrap();
+
+        let text = compiled.nested_disassembly();
+        let assembled = Assembler::assemble(&mut vm, &text).unwrap();
+        assert_eq!(text, assembled.nested_disassembly());
+    }
+
+    #[test]
+    fn long_forms_round_trip_through_the_assembler() {
+        let mut vm = Vm::new();
+        // Enough constants in each chunk to push the rest past 256
+        let pad: String = (0..300).map(|i| format!("{i}.5;")).collect();
+        let source = "
+            PAD
+            class A { get() { return 1; } }
+            class B < A {
+                get() { PAD var m = super.get; return super.get() + m(); }
+            }
+            fun counter() {
+                PAD
+                var count = 0;
+                fun next() { count = count + 1; return count; }
+                return next;
+            }
+            var b = B();
+            b.n = 2;
+            var result = b.get() + b.n + counter()();
+        "
+        .replace("PAD", &pad);
+        let compiled = Compiler::compile(&mut vm, &source).unwrap();
+
+        let text = compiled.nested_disassembly();
+        for long in [
+            ClosureLong,
+            ClassLong,
+            GetPropertyLong,
+            SetPropertyLong,
+            MethodLong,
+            InvokeLong,
+            GetSuperLong,
+            SuperInvokeLong,
+        ] {
+            assert!(
+                text.contains(long.name()),
+                "no {} in the listing",
+                long.name()
+            );
+        }
+
+        let assembled = Assembler::assemble(&mut vm, &text).unwrap();
+        assert_eq!(text, assembled.nested_disassembly());
+        vm.interpret(assembled).unwrap();
+        assert_eq!(Some(5.0), vm.get_global("result").unwrap().as_number());
     }
 }
@@ -0,0 +1,216 @@
+use std::fmt::Display;
+
+use crate::token::Span;
+
+// A problem found while compiling, e.g. a missing semicolon
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompileError {
+    pub line: usize,
+    pub column: usize,
+    pub lexeme: String, // empty if the error isn't at a particular token
+    pub message: String,
+    pub at_end: bool, // the error was found at the end of the source
+    pub span: Span,
+    pub snippet: Snippet,
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[line {}] Error", self.line)?;
+
+        if self.at_end {
+            write!(f, " at end")?;
+        } else if !self.lexeme.is_empty() {
+            write!(f, " at {}", self.lexeme)?;
+        }
+
+        write!(f, ": {}", self.message)?;
+        write!(f, "\n{}", self.snippet)
+    }
+}
+
+// Told about errors as they happen, e.g. to forward them to a logger. See
+// `Vm::set_error_reporter`.
+pub trait ErrorReporter {
+    fn compile_errors(&mut self, errors: &[CompileError]);
+    fn runtime_error(&mut self, error: &RuntimeError);
+}
+
+// Prints errors to stderr, as the command-line interpreter does
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StderrReporter;
+
+impl ErrorReporter for StderrReporter {
+    fn compile_errors(&mut self, errors: &[CompileError]) {
+        for error in errors {
+            eprintln!("{error}");
+        }
+    }
+
+    fn runtime_error(&mut self, error: &RuntimeError) {
+        eprintln!("{error}");
+    }
+}
+
+// A problem found while running a program, e.g. adding a string to a number
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeError {
+    pub message: String,
+    pub line: usize,
+    pub snippet: Option<Snippet>, // `None` if the source isn't available
+    pub stack_trace: Vec<StackFrame>, // innermost call first
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)?;
+
+        if let Some(snippet) = &self.snippet {
+            write!(f, "\n{snippet}")?;
+        }
+
+        // Innermost call first, e.g. "[line 12] in fib()" then "[line 20] in script"
+        for frame in &self.stack_trace {
+            write!(f, "\n{frame}")?;
+        }
+
+        Ok(())
+    }
+}
+
+// One call that was in progress when a runtime error happened
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackFrame {
+    pub function: String, // e.g. "fib()" or "script"
+    pub line: usize,
+}
+
+impl Display for StackFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[line {}] in {}", self.line, self.function)
+    }
+}
+
+// A problem found while loading compiled bytecode, see `bytecode::deserialize`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadError {
+    NotBytecode,             // the magic number is missing
+    UnsupportedVersion(u16), // written by a different version of clox
+    ChecksumMismatch,        // the file has been corrupted
+    Malformed(&'static str), // the checksum matched but the contents don't make sense
+    Invalid(VerifyError),    // the bytecode could crash the VM
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::NotBytecode => write!(f, "Not a compiled Lox file."),
+            LoadError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported bytecode version {version}.")
+            }
+            LoadError::ChecksumMismatch => write!(f, "Checksum mismatch, the file is corrupt."),
+            LoadError::Malformed(reason) => write!(f, "Malformed bytecode: {reason}."),
+            LoadError::Invalid(error) => write!(f, "Invalid bytecode: {error}"),
+        }
+    }
+}
+
+// A problem found while assembling a listing, see `Assembler::assemble`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssembleError {
+    pub line: usize, // of the listing
+    pub message: String,
+}
+
+impl Display for AssembleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[line {}] Error: {}", self.line, self.message)
+    }
+}
+
+// A problem found by `Function::verify`, e.g. a jump into the middle of an instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyError {
+    pub function: String, // e.g. "fib()" or "script"
+    pub offset: usize,    // of the instruction with the problem
+    pub message: String,
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "[offset {:04} in {}] {}",
+            self.offset, self.function, self.message
+        )
+    }
+}
+
+// The line of source code an error points at. It's displayed with the part
+// that caused the error underlined, e.g.
+//
+//   |
+// 2 |   print -nil;
+//   |         ^
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snippet {
+    pub line: usize,
+    pub column: usize, // 1-based, counted in characters
+    pub width: usize,  // number of characters to underline
+    pub text: String,
+}
+
+impl Snippet {
+    pub fn new(source: &str, span: Span) -> Self {
+        let line_start = source[..span.start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[span.start..]
+            .find('\n')
+            .map_or(source.len(), |i| span.start + i);
+
+        // Tokens that run onto later lines (i.e. strings) are only underlined
+        // up to the end of the first one
+        let end = span.end.clamp(span.start, line_end);
+
+        Self {
+            line: source[..span.start].matches('\n').count() + 1,
+            column: source[line_start..span.start].chars().count() + 1,
+            width: source[span.start..end].chars().count().max(1),
+            text: source[line_start..line_end].trim_end().to_string(),
+        }
+    }
+}
+
+impl Display for Snippet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let gutter = " ".repeat(self.line.to_string().len());
+
+        // Keep any tabs so the underline lines up with the text above it
+        let indent: String = self
+            .text
+            .chars()
+            .take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        writeln!(f, "{gutter} |")?;
+        writeln!(f, "{} | {}", self.line, self.text)?;
+        write!(f, "{gutter} | {indent}^{}", "~".repeat(self.width - 1))
+    }
+}
+
+mod tests {
+    #[allow(unused)]
+    use super::*;
+
+    #[test]
+    fn snippet_underlines_span() {
+        let source = "var a = 1;\n\tprint a + nil;\n";
+        let snippet = Snippet::new(source, Span { start: 22, end: 25 });
+
+        assert_eq!((2, 12, 3), (snippet.line, snippet.column, snippet.width));
+        assert_eq!(
+            "  |\n2 | \tprint a + nil;\n  | \t          ^~~",
+            snippet.to_string()
+        );
+    }
+}
@@ -0,0 +1,60 @@
+use std::fmt::Display;
+
+use crate::chunk::{Chunk, LineRun, SpanRun};
+use crate::gc::{Gc, Trace, Tracer};
+use crate::string::LoxString;
+use crate::value::Value;
+
+#[derive(Debug, Default)]
+pub struct Function {
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+    pub(crate) name: Option<Gc<LoxString>>, // `None` for the top-level script
+}
+
+impl Function {
+    pub fn new(name: Option<Gc<LoxString>>) -> Self {
+        Self {
+            name,
+            ..Default::default()
+        }
+    }
+
+    // The name to use when reporting errors, e.g. "fib()" or "script"
+    pub fn display_name(&self) -> String {
+        match &self.name {
+            Some(name) => format!("{}()", name.as_str()),
+            None => "script".to_string(),
+        }
+    }
+}
+
+impl Display for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "<fn {}>", name.as_str()),
+            None => write!(f, "<script>"),
+        }
+    }
+}
+
+impl Trace for Function {
+    fn trace(&self, tracer: &mut Tracer) {
+        if let Some(name) = self.name {
+            tracer.mark(name);
+        }
+
+        for constant in &self.chunk.constants {
+            tracer.mark_value(constant);
+        }
+    }
+
+    fn heap_size(&self) -> usize {
+        let chunk = &self.chunk;
+        chunk.code.capacity()
+            + chunk.constants.capacity() * size_of::<Value>()
+            + chunk.lines.capacity() * size_of::<LineRun>()
+            + chunk.spans.capacity() * size_of::<SpanRun>()
+    }
+}
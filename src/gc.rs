@@ -0,0 +1,397 @@
+use std::cell::{Cell, RefCell};
+use std::fmt::Debug;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::ptr::NonNull;
+use std::rc::Rc;
+
+use crate::object::Object;
+use crate::value::Value;
+
+const GC_HEAP_GROW_FACTOR: usize = 2;
+const GC_INITIAL_THRESHOLD: usize = 1024 * 1024;
+
+// Anything that lives on the heap has to tell the collector which other heap
+// objects it refers to so that they can be kept alive.
+pub trait Trace {
+    fn trace(&self, tracer: &mut Tracer);
+
+    // Bytes the object owns outside of its own allocation, e.g. a string's
+    // characters, so that they count towards the next collection too
+    fn heap_size(&self) -> usize {
+        0
+    }
+}
+
+struct GcBox<T: ?Sized> {
+    marked: Cell<bool>,
+    size: usize, // what was added to `bytes_allocated` for this object
+    value: T,
+}
+
+// A pointer to an object owned by the `Heap`. These are cheap to copy around
+// but they're only valid for as long as the object is reachable from one of
+// the VM's roots, so anything that allocates has to make sure the objects it
+// still needs are reachable first (usually by leaving them on the stack).
+// That can't be checked, so code outside the crate never gets hold of a `Gc`
+// and uses a `Root` instead.
+pub struct Gc<T: ?Sized> {
+    ptr: NonNull<GcBox<T>>,
+}
+
+impl<T: ?Sized> Gc<T> {
+    pub fn is_marked(&self) -> bool {
+        // SAFETY: the object hasn't been swept so the pointer is still valid.
+        unsafe { self.ptr.as_ref().marked.get() }
+    }
+}
+
+impl<T: ?Sized> Clone for Gc<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: ?Sized> Copy for Gc<T> {}
+
+impl<T: ?Sized> Deref for Gc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: the object hasn't been swept so the pointer is still valid.
+        unsafe { &self.ptr.as_ref().value }
+    }
+}
+
+// Heap objects are compared and hashed by identity. This is also correct for
+// strings because they're interned.
+impl<T: ?Sized> PartialEq for Gc<T> {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::addr_eq(self.ptr.as_ptr(), other.ptr.as_ptr())
+    }
+}
+
+impl<T: ?Sized> Eq for Gc<T> {}
+
+impl<T: ?Sized> Hash for Gc<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.ptr.cast::<()>().hash(state);
+    }
+}
+
+// Objects can refer to each other in cycles so we don't try to print what a
+// `Gc` points at.
+impl<T: ?Sized> Debug for Gc<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Gc({:p})", self.ptr)
+    }
+}
+
+// Marks objects during a collection. Marked objects are kept on a "gray"
+// worklist until the objects they refer to have been marked too.
+#[derive(Default)]
+pub struct Tracer {
+    gray: Vec<NonNull<GcBox<dyn Trace>>>,
+}
+
+impl Tracer {
+    pub fn mark<T: Trace + 'static>(&mut self, gc: Gc<T>) {
+        if gc.is_marked() {
+            return;
+        }
+
+        // SAFETY: the object hasn't been swept so the pointer is still valid.
+        unsafe { gc.ptr.as_ref().marked.set(true) };
+        self.gray.push(gc.ptr);
+    }
+
+    pub fn mark_value(&mut self, value: &Value) {
+        if let Value::Obj(object) = value {
+            self.mark_object(*object);
+        }
+    }
+
+    pub(crate) fn mark_object(&mut self, object: Object) {
+        match object {
+            Object::Str(string) => self.mark(string),
+            Object::Function(function) => self.mark(function),
+            Object::Closure(closure) => self.mark(closure),
+            Object::Class(class) => self.mark(class),
+            Object::Instance(instance) => self.mark(instance),
+            Object::BoundMethod(bound_method) => self.mark(bound_method),
+            Object::Native(native) => self.mark(native),
+        }
+    }
+
+    // Keeps marking until every reachable object has been found
+    pub fn trace_references(&mut self) {
+        while let Some(ptr) = self.gray.pop() {
+            // SAFETY: the object hasn't been swept so the pointer is still valid.
+            unsafe { ptr.as_ref().value.trace(self) };
+        }
+    }
+}
+
+// A value that's kept alive for as long as the handle exists, which is how the
+// VM hands objects to code outside it. Outside the crate the value can only be
+// looked at through the handle (see the accessors on `Root<Value>`) so that
+// nothing can hold on to an object after the handle has gone.
+pub struct Root<T: Copy> {
+    value: T,
+    pin: Option<Pin>, // `None` if the value isn't an object
+}
+
+struct Pin {
+    object: Object,
+    objects: Rc<Objects>, // so that the object outlives the VM if need be
+}
+
+impl<T: Copy> Root<T> {
+    pub(crate) fn new(heap: &Heap, value: T, as_value: Value) -> Self {
+        let pin = match as_value {
+            Value::Obj(object) => {
+                heap.objects.pinned.borrow_mut().push(object);
+                Some(Pin {
+                    object,
+                    objects: Rc::clone(&heap.objects),
+                })
+            }
+            _ => None,
+        };
+
+        Self { value, pin }
+    }
+
+    // Copies of the value are only valid while something keeps them alive
+    pub(crate) fn value(&self) -> &T {
+        &self.value
+    }
+
+    // Like `value` but for handing the value back to a VM, which has to be the
+    // one that it came from
+    pub(crate) fn value_for(&self, heap: &Heap) -> T {
+        if let Some(pin) = &self.pin {
+            assert!(
+                Rc::ptr_eq(&pin.objects, &heap.objects),
+                "value belongs to a different VM"
+            );
+        }
+        self.value
+    }
+}
+
+// Numbers, booleans and nil aren't on the heap so there's nothing to pin
+impl From<f64> for Root<Value> {
+    fn from(number: f64) -> Self {
+        Self {
+            value: Value::Number(number),
+            pin: None,
+        }
+    }
+}
+
+impl From<bool> for Root<Value> {
+    fn from(b: bool) -> Self {
+        Self {
+            value: Value::Bool(b),
+            pin: None,
+        }
+    }
+}
+
+impl From<()> for Root<Value> {
+    fn from(_: ()) -> Self {
+        Self {
+            value: Value::Nil,
+            pin: None,
+        }
+    }
+}
+
+// The object stays put for as long as the root, and so does the reference
+impl<T: ?Sized> Deref for Root<Gc<T>> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.value
+    }
+}
+
+impl<T: Copy> Clone for Root<T> {
+    fn clone(&self) -> Self {
+        let pin = self.pin.as_ref().map(|pin| {
+            pin.objects.pinned.borrow_mut().push(pin.object);
+            Pin {
+                object: pin.object,
+                objects: Rc::clone(&pin.objects),
+            }
+        });
+
+        Self {
+            value: self.value,
+            pin,
+        }
+    }
+}
+
+impl Drop for Pin {
+    fn drop(&mut self) {
+        let mut pinned = self.objects.pinned.borrow_mut();
+        if let Some(index) = pinned.iter().position(|&other| other == self.object) {
+            pinned.swap_remove(index);
+        }
+    }
+}
+
+impl<T: Copy + PartialEq> PartialEq for Root<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.value == other.value
+    }
+}
+
+impl<T: Copy + Debug> Debug for Root<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Root").field(&self.value).finish()
+    }
+}
+
+impl<T: Copy + std::fmt::Display> std::fmt::Display for Root<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.value.fmt(f)
+    }
+}
+
+// Every object on the heap and which of them are pinned by a `Root`. This is
+// shared with the roots so that nothing is freed until the VM and every root
+// have been dropped.
+#[derive(Default)]
+struct Objects {
+    all: RefCell<Vec<NonNull<GcBox<dyn Trace>>>>,
+    pinned: RefCell<Vec<Object>>, // may contain duplicates, one per root
+}
+
+impl Drop for Objects {
+    fn drop(&mut self) {
+        for ptr in self.all.get_mut().drain(..) {
+            // SAFETY: every pointer in `all` came from `Box::leak` and hasn't
+            // been freed yet.
+            drop(unsafe { Box::from_raw(ptr.as_ptr()) });
+        }
+    }
+}
+
+pub(crate) struct Heap {
+    objects: Rc<Objects>,
+    bytes_allocated: usize,
+    next_gc: usize,
+    stress: bool, // collect before every allocation
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self {
+            objects: Rc::default(),
+            bytes_allocated: 0,
+            next_gc: GC_INITIAL_THRESHOLD,
+            stress: false,
+        }
+    }
+}
+
+impl Debug for Heap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Heap")
+            .field("objects", &self.objects.all.borrow().len())
+            .field("bytes_allocated", &self.bytes_allocated)
+            .field("next_gc", &self.next_gc)
+            .field("stress", &self.stress)
+            .finish()
+    }
+}
+
+impl Heap {
+    pub fn alloc<T: Trace + 'static>(&mut self, value: T) -> Gc<T> {
+        let size = std::mem::size_of::<GcBox<T>>() + value.heap_size();
+        let gc_box = Box::new(GcBox {
+            marked: Cell::new(false),
+            size,
+            value,
+        });
+        self.bytes_allocated += size;
+
+        let ptr = NonNull::from(Box::leak(gc_box));
+        self.objects.all.borrow_mut().push(ptr);
+        Gc { ptr }
+    }
+
+    // The objects that are being kept alive by a `Root`
+    pub fn mark_pinned(&self, tracer: &mut Tracer) {
+        for object in self.objects.pinned.borrow().iter() {
+            tracer.mark_object(*object);
+        }
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.stress || self.bytes_allocated > self.next_gc
+    }
+
+    pub fn set_stress(&mut self, stress: bool) {
+        self.stress = stress;
+    }
+
+    // Frees every object that wasn't marked and clears the marks on the rest,
+    // ready for the next collection.
+    pub fn sweep(&mut self) {
+        let mut bytes_allocated = self.bytes_allocated;
+
+        self.objects.all.borrow_mut().retain(|ptr| {
+            // SAFETY: every pointer in `all` came from `Box::leak` and
+            // hasn't been freed yet.
+            let gc_box = unsafe { ptr.as_ref() };
+            if gc_box.marked.replace(false) {
+                return true;
+            }
+
+            bytes_allocated -= gc_box.size;
+            // SAFETY: the object is unreachable so nothing can use it anymore.
+            drop(unsafe { Box::from_raw(ptr.as_ptr()) });
+            false
+        });
+
+        self.bytes_allocated = bytes_allocated;
+        self.next_gc = (bytes_allocated * GC_HEAP_GROW_FACTOR).max(GC_INITIAL_THRESHOLD);
+    }
+}
+
+mod tests {
+    #[allow(unused)]
+    use super::*;
+    #[allow(unused)]
+    use crate::string::LoxString;
+
+    #[test]
+    fn sweep_frees_unmarked_objects() {
+        let mut heap = Heap::default();
+        let kept = heap.alloc(LoxString::from("kept".to_string()));
+        heap.alloc(LoxString::from("garbage".to_string()));
+
+        let mut tracer = Tracer::default();
+        tracer.mark(kept);
+        tracer.trace_references();
+        heap.sweep();
+
+        assert_eq!(1, heap.objects.all.borrow().len());
+        assert_eq!("kept", kept.as_str());
+        assert!(!kept.is_marked());
+    }
+
+    #[test]
+    fn counts_string_contents() {
+        let mut heap = Heap::default();
+        heap.alloc(LoxString::from("y".repeat(100_000)));
+        assert!(heap.bytes_allocated > 100_000);
+
+        heap.sweep();
+        assert_eq!(0, heap.bytes_allocated);
+    }
+}
@@ -0,0 +1,41 @@
+//! A bytecode virtual machine for the Lox programming language.
+//!
+//! The simplest way to run Lox code is [`Vm::interpret_source`]:
+//!
+//! ```
+//! let mut vm = clox::Vm::new();
+//! vm.interpret_source("var greeting = \"hello\";").unwrap();
+//! assert_eq!(Some("hello"), vm.get_global("greeting").unwrap().as_str());
+//! ```
+
+pub mod assembler;
+pub mod bytecode;
+pub mod chunk;
+pub mod class;
+pub mod closure;
+pub mod compiler;
+pub mod debug;
+pub mod error;
+pub mod function;
+pub mod gc;
+pub mod native;
+pub mod object;
+pub mod scanner;
+pub mod string;
+pub mod token;
+pub mod value;
+mod verify;
+pub mod vm;
+
+pub use assembler::Assembler;
+pub use chunk::{Chunk, OpCode};
+pub use compiler::Compiler;
+pub use debug::Instruction;
+pub use error::{
+    AssembleError, CompileError, ErrorReporter, LoadError, RuntimeError, StackFrame,
+    StderrReporter, VerifyError,
+};
+pub use gc::Root;
+pub use scanner::Scanner;
+pub use value::Value;
+pub use vm::{InterpretResult, Vm, VmError};
@@ -1,68 +1,227 @@
-use std::io::{BufRead, Write};
+use std::path::Path;
 
-use compiler::Compiler;
-use vm::{Vm, VmError};
+use clox::compiler::MAX_COMPILE_ERRORS;
+use clox::{bytecode, Compiler, StderrReporter, Vm, VmError};
 
-mod chunk;
-mod compiler;
-mod debug;
-mod object;
-mod scanner;
-mod string;
-mod token;
-mod value;
-mod vm;
+mod repl;
 
-fn main() {
-    let vm = Vm::new();
-    let args: Vec<String> = std::env::args().collect();
-
-    if args.len() == 1 {
-        repl(vm);
-    } else if args.len() == 2 {
-        run_file(vm, &args[1]);
-    } else {
-        eprintln!("Usage: clox [path]");
-        std::process::exit(64);
+const USAGE: &str = "\
+Usage: clox [options] [path | -e <source>] [-- args...]
+       clox compile [options] <path | -e <source>> [-o <output>]
+       clox run [options] <path> [-- args...]
+
+`compile` saves the bytecode for a script, by default next to it with a
+.loxc extension, and `run` runs the saved bytecode without recompiling.
+
+Options:
+  -e <source>      Run <source> instead of a file
+  -o <output>      Where `compile` saves the bytecode
+  --disassemble    Print the bytecode for the script without running it
+  --print-code     Print the bytecode for each function as it's compiled
+  --trace          Print each instruction and the stack as it's executed
+  --gc-stress      Collect garbage before every allocation
+  -h, --help       Print this message
+
+Arguments after `--` are passed to the script, which can read them with
+`argc()` and `arg(n)`.";
+
+#[derive(Debug, Default, PartialEq, Eq)]
+enum Command {
+    #[default]
+    Interpret, // compile and run source code
+    Compile,
+    Run,
+}
+
+#[derive(Debug, Default)]
+struct Options {
+    command: Command,
+    path: Option<String>,
+    source: Option<String>, // from `-e`
+    output: Option<String>, // from `-o`
+    disassemble: bool,
+    print_code: bool,
+    trace: bool,
+    gc_stress: bool,
+    script_args: Vec<String>,
+}
+
+impl Options {
+    fn parse(args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut options = Self::default();
+        let mut args = args.peekable();
+
+        options.command = match args.next_if(|arg| arg == "compile" || arg == "run") {
+            Some(command) if command == "compile" => Command::Compile,
+            Some(_) => Command::Run,
+            None => Command::Interpret,
+        };
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--" => {
+                    options.script_args = args.collect();
+                    break;
+                }
+                "-e" => match args.next() {
+                    Some(source) => options.source = Some(source),
+                    None => return Err("-e needs some source code to run".to_string()),
+                },
+                "-o" => match args.next() {
+                    Some(output) => options.output = Some(output),
+                    None => return Err("-o needs a path to save the bytecode to".to_string()),
+                },
+                "--disassemble" => options.disassemble = true,
+                "--print-code" => options.print_code = true,
+                "--trace" => options.trace = true,
+                "--gc-stress" => options.gc_stress = true,
+                "-h" | "--help" => {
+                    println!("{USAGE}");
+                    std::process::exit(0);
+                }
+                _ if arg.starts_with('-') => return Err(format!("unknown option '{arg}'")),
+                _ if options.path.is_some() => return Err("expected only one path".to_string()),
+                _ => options.path = Some(arg),
+            }
+        }
+
+        if options.path.is_some() && options.source.is_some() {
+            return Err("can't run both a file and -e".to_string());
+        }
+
+        if options.output.is_some() && options.command != Command::Compile {
+            return Err("-o only makes sense with compile".to_string());
+        }
+
+        if options.command == Command::Run && options.source.is_some() {
+            return Err("run needs the path of a compiled script, not -e".to_string());
+        }
+
+        Ok(options)
     }
 }
 
-fn repl(mut vm: Vm) {
-    let stdin = std::io::stdin();
-    let mut stdin = stdin.lock();
-    let mut buffer = String::with_capacity(1024);
-    print_prompt();
+fn main() {
+    let mut options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("error: {message}\n\n{USAGE}");
+            std::process::exit(64);
+        }
+    };
+
+    let mut vm = Vm::new();
+    vm.set_print_code(options.print_code || options.disassemble);
+    vm.set_trace_execution(options.trace);
+    // Collecting garbage as often as possible helps to shake out GC bugs
+    vm.set_gc_stress(options.gc_stress);
+    vm.set_args(std::mem::take(&mut options.script_args));
+    vm.set_error_reporter(StderrReporter);
+
+    match options.command {
+        Command::Interpret => {}
+        Command::Compile => return compile(&mut vm, options),
+        Command::Run => return run(&mut vm, options),
+    }
 
-    while stdin.read_line(&mut buffer).is_ok() {
-        let source = buffer.trim();
-        if let Ok(chunk) = Compiler::compile(&mut vm, source) {
-            vm.interpret(chunk);
+    let source = match (&options.path, options.source) {
+        (Some(path), _) => std::fs::read_to_string(path).expect("error reading file"),
+        (None, Some(source)) => source,
+        (None, None) if options.disassemble => {
+            eprintln!("error: --disassemble needs a path or -e\n\n{USAGE}");
+            std::process::exit(64);
         }
+        (None, None) => return repl::run(vm),
+    };
 
-        buffer.clear();
-        print_prompt();
+    if options.disassemble {
+        // Compiling with `print_code` set prints each function's bytecode.
+        // This bypasses the VM so errors have to be printed here.
+        if let Err(error) = Compiler::compile(&mut vm, &source) {
+            eprintln!("{error}");
+            exit_with_error(error);
+        }
+    } else if let Err(error) = vm.interpret_source(&source) {
+        exit_with_error(error);
     }
 }
 
-fn run_file(mut vm: Vm, path: &str) {
-    let source = std::fs::read_to_string(path).expect("error reading file");
+// Compiles a script and saves its bytecode instead of running it
+fn compile(vm: &mut Vm, options: Options) {
+    let (source, output) = match (options.path, options.source, options.output) {
+        (Some(path), _, output) => {
+            let source = std::fs::read_to_string(&path).expect("error reading file");
+            let output = output.unwrap_or_else(|| {
+                let output = Path::new(&path).with_extension("loxc");
+                output.to_string_lossy().into_owned()
+            });
+            (source, output)
+        }
+        (None, Some(source), Some(output)) => (source, output),
+        (None, Some(_), None) => {
+            eprintln!("error: compile -e needs -o\n\n{USAGE}");
+            std::process::exit(64);
+        }
+        (None, None, _) => {
+            eprintln!("error: compile needs a path or -e\n\n{USAGE}");
+            std::process::exit(64);
+        }
+    };
+
+    // This bypasses the VM so errors have to be printed here
+    let script = Compiler::compile(vm, &source).unwrap_or_else(|error| {
+        eprintln!("{error}");
+        exit_with_error(error)
+    });
 
-    let chunk = match Compiler::compile(&mut vm, &source) {
-        Ok(chunk) => chunk,
-        Err(_) => {
-            eprintln!("couldn't compile source");
+    if let Err(error) = std::fs::write(&output, bytecode::serialize(&script)) {
+        eprintln!("error: couldn't write {output}: {error}");
+        std::process::exit(74);
+    }
+}
+
+// Runs a script saved by `compile`
+fn run(vm: &mut Vm, options: Options) {
+    let Some(path) = options.path else {
+        eprintln!("error: run needs a path\n\n{USAGE}");
+        std::process::exit(64);
+    };
+
+    let bytes = std::fs::read(&path).expect("error reading file");
+    let script = match bytecode::deserialize(vm, &bytes) {
+        Ok(script) => script,
+        Err(error) => {
+            eprintln!("error: couldn't load {path}: {error}");
             std::process::exit(65);
         }
     };
 
-    match vm.interpret(chunk) {
-        Ok(_) => {}
-        Err(VmError::CompileError) => std::process::exit(65),
-        Err(VmError::RuntimeError) => std::process::exit(70),
+    if options.disassemble {
+        // In the same order as `--print-code` prints them while compiling
+        let listing = script.nested_disassembly();
+        if let Err(error) = vm.output().write_all(listing.as_bytes()) {
+            eprintln!("error: couldn't print the listing: {error}");
+            std::process::exit(74);
+        }
+    } else if let Err(error) = vm.interpret(script) {
+        exit_with_error(error);
     }
 }
 
-fn print_prompt() {
-    print!("> ");
-    std::io::stdout().flush().expect("error flushing stdout");
+// Exits with the status code for the error. The details have already been
+// printed by the time this is called.
+fn exit_with_error(error: VmError) -> ! {
+    match error {
+        VmError::CompileError(errors) => {
+            let count = errors.len();
+            let plural = if count == 1 { "" } else { "s" };
+            if count >= MAX_COMPILE_ERRORS {
+                eprintln!("couldn't compile source: stopped after {count} errors");
+            } else {
+                eprintln!("couldn't compile source: found {count} error{plural}");
+            }
+            std::process::exit(65);
+        }
+        VmError::RuntimeError(_) => std::process::exit(70),
+    }
 }
@@ -0,0 +1,118 @@
+use std::fmt::Display;
+use std::io::BufRead;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::gc::{Gc, Root, Trace, Tracer};
+use crate::string::LoxString;
+use crate::value::Value;
+use crate::vm::Vm;
+
+// Natives receive their arguments as roots, so they can hold on to them after
+// the call, and return a root too. Returning an error aborts the script with
+// a runtime error.
+pub type NativeFn = dyn Fn(&mut Vm, &[Root<Value>]) -> Result<Root<Value>, String>;
+
+pub struct Native {
+    pub name: Gc<LoxString>,
+    pub arity: usize,
+    pub function: Box<NativeFn>,
+}
+
+impl Native {
+    pub fn new(name: Gc<LoxString>, arity: usize, function: Box<NativeFn>) -> Self {
+        Self {
+            name,
+            arity,
+            function,
+        }
+    }
+}
+
+impl std::fmt::Debug for Native {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Native")
+            .field("name", &self.name.as_str())
+            .field("arity", &self.arity)
+            .finish()
+    }
+}
+
+impl Display for Native {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native fn>")
+    }
+}
+
+impl Trace for Native {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.mark(self.name);
+    }
+}
+
+// Installs the natives that every script can use
+pub fn define_builtins(vm: &mut Vm) {
+    vm.define_native("clock", 0, clock);
+    vm.define_native("sleep", 1, sleep);
+    vm.define_native("input", 0, input);
+    vm.define_native("argc", 0, argc);
+    vm.define_native("arg", 1, arg);
+}
+
+// Seconds since the Unix epoch
+fn clock(_vm: &mut Vm, _args: &[Root<Value>]) -> Result<Root<Value>, String> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|error| error.to_string())?;
+    Ok(Root::from(now.as_secs_f64()))
+}
+
+fn sleep(_vm: &mut Vm, args: &[Root<Value>]) -> Result<Root<Value>, String> {
+    // Rules out negative numbers, NaN, infinity and anything else too big
+    let duration = args[0]
+        .as_number()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok());
+
+    match duration {
+        Some(duration) => {
+            std::thread::sleep(duration);
+            Ok(Root::from(()))
+        }
+        None => Err("Argument to sleep() must be a finite, non-negative number.".to_string()),
+    }
+}
+
+// The number of command-line arguments passed to the script
+fn argc(vm: &mut Vm, _args: &[Root<Value>]) -> Result<Root<Value>, String> {
+    Ok(Root::from(vm.args().len() as f64))
+}
+
+// The nth command-line argument (counting from zero) or `nil` if there are
+// fewer than n + 1
+fn arg(vm: &mut Vm, args: &[Root<Value>]) -> Result<Root<Value>, String> {
+    match args[0].as_number() {
+        Some(n) if n >= 0.0 && n.fract() == 0.0 => match vm.args().get(n as usize).cloned() {
+            Some(arg) => Ok(vm.new_string(&arg)),
+            None => Ok(Root::from(())),
+        },
+        _ => Err("Argument to arg() must be a non-negative integer.".to_string()),
+    }
+}
+
+// Reads a line from stdin without its trailing newline. Returns `nil` at the
+// end of input.
+fn input(vm: &mut Vm, _args: &[Root<Value>]) -> Result<Root<Value>, String> {
+    let mut line = String::new();
+    let bytes_read = std::io::stdin()
+        .lock()
+        .read_line(&mut line)
+        .map_err(|error| error.to_string())?;
+
+    if bytes_read == 0 {
+        return Ok(Root::from(()));
+    }
+
+    let trimmed_len = line.trim_end_matches(['\n', '\r']).len();
+    line.truncate(trimmed_len);
+
+    Ok(vm.new_string(&line))
+}
@@ -1,26 +1,53 @@
 use std::fmt::Display;
 
+use crate::class::{BoundMethod, Class, Instance};
+use crate::closure::Closure;
+use crate::function::Function;
+use crate::gc::Gc;
+use crate::native::Native;
 use crate::string::LoxString;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub enum Object {
-    Str(LoxString),
+// Every variant points into the VM's heap. Two objects are only equal if
+// they're the same object (strings are interned so this works for them too).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Object {
+    Str(Gc<LoxString>),
+    Function(Gc<Function>),
+    Closure(Gc<Closure>),
+    Class(Gc<Class>),
+    Instance(Gc<Instance>),
+    BoundMethod(Gc<BoundMethod>),
+    Native(Gc<Native>),
 }
 
 impl Display for Object {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Object::Str(lox_string) => write!(f, "{}", lox_string),
+            Object::Str(lox_string) => write!(f, "{}", **lox_string),
+            Object::Function(function) => write!(f, "{}", **function),
+            Object::Closure(closure) => write!(f, "{}", **closure),
+            Object::Class(class) => write!(f, "{}", **class),
+            Object::Instance(instance) => write!(f, "{}", **instance),
+            Object::BoundMethod(bound_method) => write!(f, "{}", **bound_method),
+            Object::Native(native) => write!(f, "{}", **native),
         }
     }
 }
 
 impl Object {
-    pub fn as_string(&self) -> &LoxString {
+    pub fn as_string(&self) -> Gc<LoxString> {
         if let Object::Str(string) = self {
-            string
+            *string
         } else {
             panic!("Object wasn't a string.");
         }
     }
+
+    pub fn as_function(&self) -> Gc<Function> {
+        if let Object::Function(function) = self {
+            *function
+        } else {
+            panic!("Object wasn't a function.");
+        }
+    }
 }
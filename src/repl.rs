@@ -0,0 +1,279 @@
+use std::path::PathBuf;
+use std::time::Instant;
+
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{CompletionType, Config, Context, Editor, Helper};
+
+use clox::scanner::KEYWORDS;
+use clox::token::TokenType;
+use clox::{Compiler, Scanner, Vm};
+
+const HELP: &str = "\
+:globals         List the global variables
+:dis <name>      Disassemble a function, or each method of a class
+:dis <code>      Disassemble some code without running it
+:stack           Show what's on the VM's stack
+:load <path>     Run a file
+:reset           Forget every global variable
+:time <code>     Run some code and show how long it took
+:help            Show this message";
+
+pub fn run(mut vm: Vm) {
+    let config = Config::builder()
+        .completion_type(CompletionType::List)
+        .auto_add_history(false)
+        .build();
+    let mut editor: Editor<LoxHelper, DefaultHistory> =
+        Editor::with_config(config).expect("error setting up line editor");
+    editor.set_helper(Some(LoxHelper::default()));
+
+    // There's no history the first time around
+    let history = history_path();
+    if let Some(path) = &history {
+        let _ = editor.load_history(path);
+    }
+
+    let mut buffer = String::with_capacity(1024);
+    loop {
+        // Complete whatever has been defined so far
+        if let Some(helper) = editor.helper_mut() {
+            helper.globals = vm.globals().map(|(name, _)| name.to_string()).collect();
+        }
+
+        let prompt = if buffer.is_empty() { "> " } else { "... " };
+        match editor.readline(prompt) {
+            Ok(line) => {
+                buffer.push_str(&line);
+                buffer.push('\n');
+            }
+            // Like most shells, ^C throws away the current input
+            Err(ReadlineError::Interrupted) => {
+                buffer.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(error) => {
+                eprintln!("Couldn't read input: {error}");
+                break;
+            }
+        }
+
+        // Keep reading until e.g. a block has been closed
+        if !buffer.starts_with(':') && is_incomplete(&buffer) {
+            continue;
+        }
+
+        if !buffer.trim().is_empty() {
+            let _ = editor.add_history_entry(buffer.trim());
+        }
+
+        // Meta-commands always fit on one line
+        if buffer.starts_with(':') {
+            meta_command(&mut vm, buffer.trim());
+        } else {
+            // Errors have already been printed by the reporter
+            let _ = vm.interpret_repl(buffer.trim());
+        }
+
+        buffer.clear();
+    }
+
+    if let Some(path) = &history {
+        if let Err(error) = editor.save_history(path) {
+            eprintln!("Couldn't save history to {}: {error}", path.display());
+        }
+    }
+
+    // Run whatever was left over so that any errors in it are reported
+    if !buffer.trim().is_empty() {
+        let _ = vm.interpret_repl(buffer.trim());
+    }
+    println!();
+}
+
+// Handles a line starting with `:`, e.g. `:globals`
+fn meta_command(vm: &mut Vm, line: &str) {
+    let (command, arg) = match line.split_once(char::is_whitespace) {
+        Some((command, arg)) => (command, arg.trim()),
+        None => (line, ""),
+    };
+
+    match (command, arg) {
+        (":globals", "") => globals(vm),
+        (":dis", code) if !code.is_empty() => disassemble(vm, code),
+        (":stack", "") => stack(vm),
+        (":load", path) if !path.is_empty() => match std::fs::read_to_string(path) {
+            // Errors have already been printed by the reporter
+            Ok(source) => drop(vm.interpret_source(&source)),
+            Err(error) => eprintln!("Couldn't read {path}: {error}"),
+        },
+        (":reset", "") => vm.reset(),
+        (":time", code) if !code.is_empty() => {
+            let start = Instant::now();
+            let _ = vm.interpret_repl(code);
+            println!("Took {:?}", start.elapsed());
+        }
+        (":help", "") => println!("{HELP}"),
+        _ => eprintln!("Unknown command '{line}'. Type :help for a list of commands."),
+    }
+}
+
+fn globals(vm: &Vm) {
+    let mut globals: Vec<_> = vm.globals().collect();
+    globals.sort_by_key(|(name, _)| *name);
+
+    for (name, value) in &globals {
+        println!("{name} = {value}");
+    }
+    println!(
+        "({} globals, {} interned strings)",
+        globals.len(),
+        vm.interned_string_count()
+    );
+}
+
+fn disassemble(vm: &mut Vm, code: &str) {
+    let global = vm.get_global(code);
+    match global.as_ref().and_then(|value| value.disassembly()) {
+        Some(listing) => print_listing(vm, &listing),
+        None if global.is_some_and(|value| value.is_native()) => {
+            eprintln!("{code} is a native function with no bytecode")
+        }
+        None => match Compiler::compile_repl(vm, code) {
+            Ok(function) => print_listing(vm, &function.disassembly()),
+            Err(error) => eprintln!("{error}"),
+        },
+    }
+}
+
+// Listings go wherever the VM's `print` statements go
+fn print_listing(vm: &mut Vm, listing: &str) {
+    if let Err(error) = vm.output().write_all(listing.as_bytes()) {
+        eprintln!("Couldn't print the listing: {error}");
+    }
+}
+
+fn stack(vm: &Vm) {
+    if vm.stack().is_empty() {
+        println!("(empty)");
+    }
+
+    for (slot, value) in vm.stack().iter().enumerate() {
+        println!("{slot:4} {value}");
+    }
+}
+
+// Whether `source` has unclosed brackets or strings, meaning there's more to come
+fn is_incomplete(source: &str) -> bool {
+    let mut scanner = Scanner::new(source);
+    let mut depth = 0;
+
+    loop {
+        let token = scanner.scan_token();
+        match token.token_type {
+            TokenType::LeftBrace | TokenType::LeftParen => depth += 1,
+            TokenType::RightBrace | TokenType::RightParen => depth -= 1,
+            TokenType::Error if token.lexeme == "Unterminated string." => return true,
+            TokenType::Eof => return depth > 0,
+            _ => {}
+        }
+    }
+}
+
+// History is kept in the user's home directory, if they have one
+fn history_path() -> Option<PathBuf> {
+    let home = std::env::var_os("HOME")?;
+    Some(PathBuf::from(home).join(".clox_history"))
+}
+
+// Completes keywords and global variables
+#[derive(Default)]
+struct LoxHelper {
+    globals: Vec<String>,
+}
+
+impl LoxHelper {
+    fn candidates(&self, prefix: &str) -> Vec<String> {
+        let mut candidates: Vec<String> = KEYWORDS
+            .iter()
+            .map(|&(keyword, _)| keyword)
+            .chain(self.globals.iter().map(String::as_str))
+            .filter(|word| word.starts_with(prefix))
+            .map(str::to_string)
+            .collect();
+
+        candidates.sort();
+        candidates.dedup();
+        candidates
+    }
+}
+
+impl Completer for LoxHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = word_start(line, pos);
+        if start == pos {
+            return Ok((pos, Vec::new()));
+        }
+
+        Ok((start, self.candidates(&line[start..pos])))
+    }
+}
+
+impl Hinter for LoxHelper {
+    type Hint = String;
+}
+
+impl Highlighter for LoxHelper {}
+
+impl Validator for LoxHelper {}
+
+impl Helper for LoxHelper {}
+
+// Finds the start of the identifier that ends at `pos`
+fn word_start(line: &str, pos: usize) -> usize {
+    line[..pos]
+        .char_indices()
+        .rev()
+        .take_while(|(_, c)| c.is_ascii_alphanumeric() || *c == '_')
+        .last()
+        .map_or(pos, |(index, _)| index)
+}
+
+mod tests {
+    #[allow(unused)]
+    use super::*;
+
+    #[test]
+    fn incomplete_input() {
+        assert!(is_incomplete("while (true) {"));
+        assert!(is_incomplete("print f(1,"));
+        assert!(is_incomplete("print \"two\nlines"));
+        assert!(!is_incomplete("{ print 1; }"));
+        assert!(!is_incomplete("print \"{\";"));
+        assert!(!is_incomplete("}"));
+    }
+
+    #[test]
+    fn completes_keywords_and_globals() {
+        let helper = LoxHelper {
+            globals: vec!["width".to_string(), "whale".to_string()],
+        };
+
+        assert_eq!(vec!["whale", "while"], helper.candidates("wh"));
+        assert_eq!(vec!["width"], helper.candidates("wi"));
+        assert_eq!(4, word_start("var widt", 8));
+        assert_eq!(8, word_start("print x ", 8));
+    }
+}
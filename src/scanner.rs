@@ -1,24 +1,51 @@
-use crate::token::{Token, TokenType};
+use crate::token::{Span, Token, TokenType};
+
+// Every reserved word and the token it's scanned as
+pub const KEYWORDS: [(&str, TokenType); 16] = [
+    ("and", TokenType::And),
+    ("class", TokenType::Class),
+    ("else", TokenType::Else),
+    ("false", TokenType::False),
+    ("for", TokenType::For),
+    ("fun", TokenType::Fun),
+    ("if", TokenType::If),
+    ("nil", TokenType::Nil),
+    ("or", TokenType::Or),
+    ("print", TokenType::Print),
+    ("return", TokenType::Return),
+    ("super", TokenType::Super),
+    ("this", TokenType::This),
+    ("true", TokenType::True),
+    ("var", TokenType::Var),
+    ("while", TokenType::While),
+];
 
 #[derive(Debug, Copy, Clone)]
 pub struct Scanner<'src> {
+    source: &'src str,
     start: &'src str,
     current: &'src str,
     line: usize,
+    column: usize,       // column of the next character
+    start_column: usize, // column of the token being scanned
 }
 
 impl<'src> Scanner<'src> {
     pub fn new(source: &'src str) -> Self {
         Self {
+            source,
             start: source,
             current: source,
             line: 1,
+            column: 1,
+            start_column: 1,
         }
     }
 
     pub fn scan_token(&mut self) -> Token<'src> {
         self.skip_whitespace();
         self.start = self.current;
+        self.start_column = self.column;
 
         if self.is_at_end() {
             return self.make_token(TokenType::Eof);
@@ -84,6 +111,8 @@ impl<'src> Scanner<'src> {
             token_type,
             lexeme: slice_to(self.start, self.current),
             line: self.line,
+            column: self.start_column,
+            span: self.span(),
         }
     }
 
@@ -93,9 +122,24 @@ impl<'src> Scanner<'src> {
             token_type: TokenType::Error,
             lexeme: message,
             line: self.line,
+            column: self.start_column,
+            span: self.span(),
         }
     }
 
+    fn span(&self) -> Span {
+        Span {
+            start: ref_diff(self.source, self.start),
+            end: ref_diff(self.source, self.current),
+        }
+    }
+
+    fn newline(&mut self) {
+        self.line += 1;
+        self.bump_current_by(1);
+        self.column = 1;
+    }
+
     #[must_use]
     fn advance(&mut self) -> char {
         let c = self.current.chars().next().unwrap();
@@ -104,11 +148,14 @@ impl<'src> Scanner<'src> {
     }
 
     fn bump_current(&mut self, c: char) {
-        self.bump_current_by(c.len_utf8());
+        self.current = &self.current[c.len_utf8()..];
+        self.column += 1;
     }
 
+    // Only for skipping ASCII characters, which are a column each
     fn bump_current_by(&mut self, offset: usize) {
         self.current = &self.current[offset..];
+        self.column += offset;
     }
 
     #[must_use]
@@ -140,10 +187,7 @@ impl<'src> Scanner<'src> {
                 ' ' | '\r' | '\t' => {
                     self.bump_current_by(1);
                 }
-                '\n' => {
-                    self.line += 1;
-                    self.bump_current_by(1);
-                }
+                '\n' => self.newline(),
                 '/' => {
                     // Comments
                     if let Some('/') = self.peek_next() {
@@ -170,10 +214,7 @@ impl<'src> Scanner<'src> {
             match self.peek() {
                 None => return self.error_token("Unterminated string."),
                 Some('"') => break,
-                Some('\n') => {
-                    self.line += 1;
-                    self.bump_current_by(1);
-                }
+                Some('\n') => self.newline(),
                 Some(c) => {
                     self.bump_current(c);
                 }
@@ -223,25 +264,11 @@ impl<'src> Scanner<'src> {
     }
 
     fn identifier_type(&self) -> TokenType {
-        match slice_to(self.start, self.current) {
-            "and" => TokenType::And,
-            "class" => TokenType::Class,
-            "else" => TokenType::Else,
-            "false" => TokenType::False,
-            "for" => TokenType::For,
-            "fun" => TokenType::Fun,
-            "if" => TokenType::If,
-            "nil" => TokenType::Nil,
-            "or" => TokenType::Or,
-            "print" => TokenType::Print,
-            "return" => TokenType::Return,
-            "super" => TokenType::Super,
-            "this" => TokenType::This,
-            "true" => TokenType::True,
-            "var" => TokenType::Var,
-            "while" => TokenType::While,
-            _ => TokenType::Identifier,
-        }
+        let lexeme = slice_to(self.start, self.current);
+        KEYWORDS
+            .iter()
+            .find(|(keyword, _)| *keyword == lexeme)
+            .map_or(TokenType::Identifier, |&(_, token_type)| token_type)
     }
 }
 
@@ -265,3 +292,35 @@ fn ref_diff(start: &str, end: &str) -> usize {
 fn slice_to<'a>(start: &'a str, end: &str) -> &'a str {
     &start[..ref_diff(start, end)]
 }
+
+mod tests {
+    #[allow(unused)]
+    use super::*;
+
+    #[test]
+    fn keywords_are_reserved() {
+        for (keyword, token_type) in KEYWORDS {
+            let token = Scanner::new(keyword).scan_token();
+            assert_eq!(token_type, token.token_type, "{keyword}");
+        }
+
+        // Only whole words are reserved
+        let token = Scanner::new("classy").scan_token();
+        assert_eq!(TokenType::Identifier, token.token_type);
+    }
+
+    #[test]
+    fn columns_count_characters() {
+        let mut scanner = Scanner::new("print \"héllo\" + x;\n  \"a\nb\" y");
+        let columns: Vec<_> = std::iter::from_fn(|| {
+            let token = scanner.scan_token();
+            (token.token_type != TokenType::Eof).then_some((token.line, token.column))
+        })
+        .collect();
+
+        assert_eq!(
+            vec![(1, 1), (1, 7), (1, 15), (1, 17), (1, 18), (3, 3), (3, 4)],
+            columns
+        );
+    }
+}
@@ -1,65 +1,80 @@
-use fnv::FnvHasher;
+use std::borrow::Borrow;
 use std::fmt::Display;
 use std::hash::{Hash, Hasher};
-use std::rc::Rc;
 
+use crate::gc::{Gc, Trace, Tracer};
 use crate::vm::Vm;
 
-#[derive(Debug, Clone, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct LoxString {
-    string: Rc<String>,
-    hash: u64,
+    string: String,
 }
 
-impl PartialEq<Self> for LoxString {
-    fn eq(&self, other: &Self) -> bool {
-        // We intern strings so we know that if the pointers (`Rc<String>`) that the
-        // `LoxString`s contain aren't equal, their data must also be different so we
-        // don't need to bother checking every character.
-        self.string.as_ptr() == other.string.as_ptr()
+impl From<String> for LoxString {
+    fn from(string: String) -> Self {
+        Self { string }
     }
 }
 
-impl From<Rc<String>> for LoxString {
-    fn from(string: Rc<String>) -> Self {
-        let mut hasher = FnvHasher::default();
-        string.hash(&mut hasher);
-        let hash = hasher.finish();
-
-        Self { string, hash }
+impl Display for LoxString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "\"{}\"", self.string)
     }
 }
 
-impl Hash for LoxString {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.hash.hash(state);
-    }
-}
+impl Trace for LoxString {
+    fn trace(&self, _tracer: &mut Tracer) {}
 
-impl Display for LoxString {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "\"{}\"", *self.string)
+    fn heap_size(&self) -> usize {
+        self.string.capacity()
     }
 }
 
 impl LoxString {
     // This will be useful later when we want to run something whenever we create a new string
     // TODO: impl ToString / Cow?
-    pub fn copy_string(vm: &mut Vm, string: &str) -> Self {
+    pub(crate) fn copy_string(vm: &mut Vm, string: &str) -> Gc<Self> {
         vm.intern_string(string.to_string())
     }
 
     // This will be useful later when we want to run something whenever we create a new string
-    fn take_string(vm: &mut Vm, string: String) -> Self {
+    fn take_string(vm: &mut Vm, string: String) -> Gc<Self> {
         vm.intern_string(string)
     }
 
-    pub fn add(vm: &mut Vm, a: &LoxString, b: &LoxString) -> Self {
+    pub(crate) fn add(vm: &mut Vm, a: &LoxString, b: &LoxString) -> Gc<Self> {
         let new_string = format!("{}{}", a.string, b.string);
         Self::take_string(vm, new_string)
     }
 
-    pub fn string(&self) -> Rc<String> {
-        self.string.clone()
+    pub fn as_str(&self) -> &str {
+        self.string.as_str()
+    }
+}
+
+// An entry in the VM's table of interned strings. Unlike `Gc<LoxString>`,
+// which is compared by identity, these are compared by their contents so that
+// we can look up whether a string has already been interned.
+#[derive(Debug, Clone, Copy)]
+pub struct Interned(pub Gc<LoxString>);
+
+impl PartialEq for Interned {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Eq for Interned {}
+
+impl Hash for Interned {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // Must match `str`'s `Hash` impl for `Borrow<str>` to work
+        self.0.as_str().hash(state);
+    }
+}
+
+impl Borrow<str> for Interned {
+    fn borrow(&self) -> &str {
+        self.0.as_str()
     }
 }
@@ -3,6 +3,15 @@ pub struct Token<'src> {
     pub token_type: TokenType,
     pub lexeme: &'src str,
     pub line: usize,
+    pub column: usize, // 1-based, counted in characters
+    pub span: Span,
+}
+
+// A range of bytes in the source code
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
 }
 
 impl<'src> Token<'src> {
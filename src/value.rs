@@ -1,15 +1,38 @@
 use std::cmp::{PartialEq, PartialOrd};
 use std::fmt::Display;
 
+use crate::class::Instance;
+use crate::gc::{Gc, Root};
 use crate::object::Object;
 use crate::string::LoxString;
 
-#[derive(Debug, Clone)]
+// Objects can only be taken apart inside the crate. Everywhere else they're
+// reached through a `Root<Value>`, which keeps them alive.
+#[allow(private_interfaces)]
+#[derive(Debug, Clone, Copy)]
 pub enum Value {
     Bool(bool),
     Nil,
     Number(f64),
-    Obj(Box<Object>),
+    Obj(Object),
+}
+
+impl From<f64> for Value {
+    fn from(number: f64) -> Self {
+        Value::Number(number)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl From<()> for Value {
+    fn from(_: ()) -> Self {
+        Value::Nil
+    }
 }
 
 impl Display for Value {
@@ -45,7 +68,7 @@ impl PartialEq for Value {
         match (self, other) {
             (Self::Bool(l0), Self::Bool(r0)) => l0 == r0,
             (Self::Number(l0), Self::Number(r0)) => l0 == r0,
-            (Self::Obj(l0), Self::Obj(r0)) => **l0 == **r0,
+            (Self::Obj(l0), Self::Obj(r0)) => l0 == r0,
             _ => core::mem::discriminant(self) == core::mem::discriminant(other),
         }
     }
@@ -61,23 +84,76 @@ impl Value {
         }
     }
 
-    // Assumes that the Value contains an Object and returns
-    // a reference to that Object (outside of its box).
-    pub fn as_object(&self) -> &Object {
+    pub fn is_nil(&self) -> bool {
+        matches!(self, Value::Nil)
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            Value::Number(number) => Some(*number),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Obj(Object::Str(string)) => Some(string.as_str()),
+            _ => None,
+        }
+    }
+
+    // Assumes that the Value contains an Object and returns it
+    pub(crate) fn as_object(&self) -> Object {
         if let Value::Obj(obj) = self {
-            obj.as_ref()
+            *obj
         } else {
             panic!("Value wasn't an Object.");
         }
     }
 
-    pub fn as_string(&self) -> &LoxString {
+    pub(crate) fn as_string(&self) -> Gc<LoxString> {
         self.as_object().as_string()
     }
+
+    // Unlike the other accessors, this doesn't panic because trying to use
+    // a property on something other than an instance is a runtime error.
+    pub(crate) fn as_instance(&self) -> Option<Gc<Instance>> {
+        match self {
+            Value::Obj(Object::Instance(instance)) => Some(*instance),
+            _ => None,
+        }
+    }
 }
 
-pub type ValueArray = Vec<Value>;
+// How code outside the crate looks at a value. Strings are borrowed from the
+// root so they can't outlive it.
+impl Root<Value> {
+    pub fn is_nil(&self) -> bool {
+        self.value().is_nil()
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        self.value().as_number()
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        self.value().as_bool()
+    }
 
-pub fn print_value(value: &Value) {
-    print!("{}", *value)
+    pub fn as_str(&self) -> Option<&str> {
+        self.value().as_str()
+    }
+
+    pub fn is_native(&self) -> bool {
+        matches!(self.value(), Value::Obj(Object::Native(_)))
+    }
 }
+
+pub type ValueArray = Vec<Value>;
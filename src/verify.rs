@@ -0,0 +1,469 @@
+use crate::chunk::{Chunk, OpCode, CAPTURE_LOCAL, CAPTURE_WIDE};
+use crate::error::VerifyError;
+use crate::function::Function;
+use crate::object::Object;
+use crate::value::Value;
+
+impl Function {
+    // Checks that a script's bytecode, and that of every function declared in
+    // it, can be run without crashing the VM. The compiler always produces
+    // valid bytecode so this is only needed for bytecode from elsewhere, e.g.
+    // a .loxc file or an assembled listing.
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        // The VM calls the script with no arguments, and there's nothing
+        // around it to capture variables from
+        if self.arity != 0 || self.upvalue_count != 0 {
+            return Err(VerifyError {
+                function: "script".to_string(),
+                offset: 0,
+                message: "The script can't have parameters or upvalues.".to_string(),
+            });
+        }
+
+        Verifier {
+            chunk: &self.chunk,
+            function: "script".to_string(),
+            arity: 0,
+            upvalue_count: 0,
+        }
+        .verify()
+    }
+}
+
+// What an instruction does to the stack and where execution can go next
+struct Step {
+    len: usize, // including the operands
+    pops: usize,
+    pushes: usize,
+    max_slot: Option<usize>, // the highest local slot it reads or writes
+    falls_through: bool,     // to the next instruction
+    jump: Option<usize>,
+}
+
+impl Step {
+    fn new(len: usize, pops: usize, pushes: usize) -> Self {
+        Self {
+            len,
+            pops,
+            pushes,
+            max_slot: None,
+            falls_through: true,
+            jump: None,
+        }
+    }
+}
+
+struct Verifier<'a> {
+    chunk: &'a Chunk,
+    function: String, // e.g. "fib()" or "script"
+    arity: usize,
+    upvalue_count: usize,
+}
+
+impl Verifier<'_> {
+    fn verify(&self) -> Result<(), VerifyError> {
+        let steps = self.decode()?;
+        self.check_jumps(&steps)?;
+        self.check_stack(&steps)?;
+
+        for constant in &self.chunk.constants {
+            if let Value::Obj(Object::Function(function)) = constant {
+                Verifier {
+                    chunk: &function.chunk,
+                    function: function.display_name(),
+                    arity: function.arity,
+                    upvalue_count: function.upvalue_count,
+                }
+                .verify()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn error(&self, offset: usize, message: impl Into<String>) -> VerifyError {
+        VerifyError {
+            function: self.function.clone(),
+            offset,
+            message: message.into(),
+        }
+    }
+
+    // Decodes every instruction, including unreachable ones, checking their
+    // operands. The result has a step at the offset of each instruction.
+    fn decode(&self) -> Result<Vec<Option<Step>>, VerifyError> {
+        let mut steps: Vec<Option<Step>> = Vec::new();
+        steps.resize_with(self.chunk.code.len(), || None);
+
+        let mut offset = 0;
+        while offset < self.chunk.code.len() {
+            let step = self.decode_instruction(offset)?;
+            let next = offset + step.len;
+            steps[offset] = Some(step);
+            offset = next;
+        }
+
+        Ok(steps)
+    }
+
+    fn decode_instruction(&self, offset: usize) -> Result<Step, VerifyError> {
+        use OpCode::*;
+
+        let byte = self.chunk.code[offset];
+        let opcode: OpCode = byte
+            .try_into()
+            .map_err(|_| self.error(offset, format!("Unknown opcode {byte}.")))?;
+        let operands = |len: usize| self.operands(offset, len);
+
+        // The long forms work just like the short ones, except that their
+        // constant index takes up three bytes instead of one
+        let (opcode, index_len) = match opcode.short_form() {
+            Some(short) => (short, 3),
+            None => (opcode, 1),
+        };
+
+        let step = match opcode {
+            Constant => {
+                self.constant(offset, operands(index_len)?)?;
+                Step::new(1 + index_len, 0, 1)
+            }
+            Nil | True | False => Step::new(1, 0, 1),
+            Negate | Not => Step::new(1, 1, 1),
+            Add | Subtract | Multiply | Divide | Equal | Greater | Less => Step::new(1, 2, 1),
+            Print | Pop | CloseUpvalue => Step::new(1, 1, 0),
+            Return => Step {
+                falls_through: false,
+                ..Step::new(1, 1, 0)
+            },
+            DefineGlobal => {
+                self.string(offset, operands(index_len)?)?;
+                Step::new(1 + index_len, 1, 0)
+            }
+            GetGlobal | Class => {
+                self.string(offset, operands(index_len)?)?;
+                Step::new(1 + index_len, 0, 1)
+            }
+            SetGlobal | GetProperty => {
+                self.string(offset, operands(index_len)?)?;
+                Step::new(1 + index_len, 1, 1)
+            }
+            SetProperty | GetSuper | Method => {
+                self.string(offset, operands(index_len)?)?;
+                Step::new(1 + index_len, 2, 1)
+            }
+            Inherit => Step::new(1, 2, 1),
+            GetLocal => Step {
+                max_slot: Some(operands(1)?),
+                ..Step::new(2, 0, 1)
+            },
+            SetLocal => Step {
+                max_slot: Some(operands(1)?),
+                ..Step::new(2, 1, 1)
+            },
+            GetUpvalue => {
+                self.upvalue(offset, operands(1)?)?;
+                Step::new(2, 0, 1)
+            }
+            SetUpvalue => {
+                self.upvalue(offset, operands(1)?)?;
+                Step::new(2, 1, 1)
+            }
+            Jump | JumpIfFalse | Loop => self.jump(offset, opcode, operands(2)?, 3)?,
+            Call => {
+                let arg_count = operands(1)?;
+                Step::new(2, arg_count + 1, 1)
+            }
+            Invoke | SuperInvoke => {
+                self.string(offset, operands(index_len)?)?;
+                let arg_count = self.operands(offset + index_len, 1)?;
+                // `super.method()` also pops the superclass
+                let receiver = if matches!(opcode, Invoke) { 1 } else { 2 };
+                Step::new(2 + index_len, arg_count + receiver, 1)
+            }
+            Closure => self.closure(offset, operands(index_len)?, index_len)?,
+            Wide => self.wide(offset)?,
+            ConstantLong | DefineGlobalLong | GetGlobalLong | SetGlobalLong | ClosureLong
+            | ClassLong | GetPropertyLong | SetPropertyLong | MethodLong | InvokeLong
+            | GetSuperLong | SuperInvokeLong => {
+                unreachable!("long forms were mapped to short ones")
+            }
+        };
+
+        Ok(step)
+    }
+
+    fn wide(&self, offset: usize) -> Result<Step, VerifyError> {
+        let byte = self.operands(offset, 1)?;
+        let opcode = OpCode::try_from(byte as u8)
+            .ok()
+            .filter(OpCode::has_wide_form)
+            .ok_or_else(|| self.error(offset, format!("Opcode {byte} has no wide form.")))?;
+
+        match opcode {
+            OpCode::GetLocal => Ok(Step {
+                max_slot: Some(self.operands(offset + 1, 2)?),
+                ..Step::new(4, 0, 1)
+            }),
+            OpCode::SetLocal => Ok(Step {
+                max_slot: Some(self.operands(offset + 1, 2)?),
+                ..Step::new(4, 1, 1)
+            }),
+            _ => self.jump(offset, opcode, self.operands(offset + 1, 4)?, 6),
+        }
+    }
+
+    // Reads the big-endian operand of `len` bytes after `offset`
+    fn operands(&self, offset: usize, len: usize) -> Result<usize, VerifyError> {
+        let bytes = self
+            .chunk
+            .code
+            .get(offset + 1..offset + 1 + len)
+            .ok_or_else(|| self.error(offset, "Instruction is cut off by the end of the chunk."))?;
+
+        Ok(bytes
+            .iter()
+            .fold(0, |operand, &byte| (operand << 8) | byte as usize))
+    }
+
+    fn constant(&self, offset: usize, index: usize) -> Result<Value, VerifyError> {
+        self.chunk
+            .constants
+            .get(index)
+            .copied()
+            .ok_or_else(|| self.error(offset, format!("Constant {index} doesn't exist.")))
+    }
+
+    fn string(&self, offset: usize, index: usize) -> Result<(), VerifyError> {
+        match self.constant(offset, index)? {
+            Value::Obj(Object::Str(_)) => Ok(()),
+            _ => Err(self.error(offset, format!("Constant {index} should be a string."))),
+        }
+    }
+
+    fn upvalue(&self, offset: usize, index: usize) -> Result<(), VerifyError> {
+        if index >= self.upvalue_count {
+            return Err(self.error(offset, format!("Upvalue {index} doesn't exist.")));
+        }
+
+        Ok(())
+    }
+
+    fn jump(
+        &self,
+        offset: usize,
+        opcode: OpCode,
+        distance: usize,
+        len: usize,
+    ) -> Result<Step, VerifyError> {
+        let next = offset + len;
+        let target = match opcode {
+            OpCode::Loop => next
+                .checked_sub(distance)
+                .ok_or_else(|| self.error(offset, "Loop jumps before the start of the chunk."))?,
+            _ => next + distance,
+        };
+
+        Ok(Step {
+            falls_through: matches!(opcode, OpCode::JumpIfFalse),
+            jump: Some(target),
+            ..Step::new(len, 0, 0)
+        })
+    }
+
+    // The function's upvalues are captured from the current frame's locals
+    // and upvalues, encoded as a flags byte and an index after the constant
+    // index
+    fn closure(&self, offset: usize, index: usize, index_len: usize) -> Result<Step, VerifyError> {
+        let function = match self.constant(offset, index)? {
+            Value::Obj(Object::Function(function)) => function,
+            _ => return Err(self.error(offset, format!("Constant {index} should be a function."))),
+        };
+
+        let mut max_slot = None;
+        let mut len = index_len;
+        for _ in 0..function.upvalue_count {
+            let flags = self.operands(offset + len, 1)? as u8;
+            let width = if flags & CAPTURE_WIDE != 0 { 2 } else { 1 };
+            let index = self.operands(offset + len + 1, width)?;
+            match flags & !CAPTURE_WIDE {
+                CAPTURE_LOCAL => max_slot = max_slot.max(Some(index)),
+                0 => self.upvalue(offset, index)?,
+                _ => return Err(self.error(offset, "Captured variable isn't local or an upvalue.")),
+            }
+            len += 1 + width;
+        }
+
+        Ok(Step {
+            max_slot,
+            ..Step::new(1 + len, 0, 1)
+        })
+    }
+
+    fn check_jumps(&self, steps: &[Option<Step>]) -> Result<(), VerifyError> {
+        for (offset, step) in steps.iter().enumerate() {
+            let Some(target) = step.as_ref().and_then(|step| step.jump) else {
+                continue;
+            };
+
+            if target >= steps.len() {
+                return Err(self.error(offset, "Jump goes past the end of the chunk."));
+            }
+
+            if steps[target].is_none() {
+                return Err(self.error(
+                    offset,
+                    format!("Jump to {target:04} lands in the middle of an instruction."),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Follows every path through the code, making sure that the stack is the
+    // same height whichever way an instruction is reached and that nothing
+    // pops more than it should. Slot 0 holds the function being called.
+    fn check_stack(&self, steps: &[Option<Step>]) -> Result<(), VerifyError> {
+        let mut depths: Vec<Option<usize>> = vec![None; steps.len()];
+        let mut worklist = vec![(0, self.arity + 1)];
+
+        while let Some((offset, depth)) = worklist.pop() {
+            let Some(step) = steps.get(offset).and_then(Option::as_ref) else {
+                return Err(self.error(offset, "Execution runs off the end of the chunk."));
+            };
+
+            match depths[offset] {
+                Some(expected) if expected != depth => {
+                    return Err(self.error(
+                        offset,
+                        format!(
+                            "Stack has {depth} values on one path here and {expected} on another."
+                        ),
+                    ));
+                }
+                Some(_) => continue,
+                None => depths[offset] = Some(depth),
+            }
+
+            if let Some(slot) = step.max_slot {
+                if slot >= depth {
+                    return Err(self.error(offset, format!("Local slot {slot} doesn't exist.")));
+                }
+            }
+
+            if step.pops >= depth {
+                return Err(self.error(
+                    offset,
+                    "Instruction pops more values than are on the stack.",
+                ));
+            }
+
+            let depth = depth - step.pops + step.pushes;
+            if step.falls_through {
+                worklist.push((offset + step.len, depth));
+            }
+            if let Some(jump) = step.jump {
+                worklist.push((jump, depth));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+mod tests {
+    #[allow(unused)]
+    use super::*;
+    #[allow(unused)]
+    use crate::token::Span;
+    #[allow(unused)]
+    use crate::vm::Vm;
+
+    #[allow(unused)]
+    fn chunk(code: &[u8]) -> Chunk {
+        let mut chunk = Chunk::new();
+        for &byte in code {
+            chunk.write_byte(byte, 1, Span::default());
+        }
+        chunk
+    }
+
+    #[allow(unused)]
+    fn verify(code: &[u8]) -> Result<(), String> {
+        let script = Function {
+            chunk: chunk(code),
+            ..Function::default()
+        };
+        script.verify().map_err(|error| error.message)
+    }
+
+    #[test]
+    fn compiled_code_is_valid() {
+        let mut vm = Vm::new();
+        let script = crate::Compiler::compile(
+            &mut vm,
+            "
+            class A { method() { return \"A\"; } }
+            class B < A { method() { return super.method() + \"B\"; } }
+
+            fun counter() {
+                var count = 0;
+                fun increment() { count = count + 1; return count; }
+                return increment;
+            }
+
+            for (var i = 0; i < 10 and i != 5; i = i + 1) {
+                if (i > 2) print B().method(); else print counter()();
+            }
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(Ok(()), script.verify());
+    }
+
+    #[test]
+    fn catches_invalid_code() {
+        use OpCode::*;
+
+        let nil = Nil as u8;
+        let ret = Return as u8;
+        let jump = Jump as u8;
+        let jump_if_false = JumpIfFalse as u8;
+        let pop = Pop as u8;
+
+        assert_eq!(Ok(()), verify(&[nil, ret]));
+        assert_eq!(Err("Unknown opcode 200.".into()), verify(&[200]));
+        assert_eq!(
+            Err("Constant 0 doesn't exist.".into()),
+            verify(&[Constant as u8, 0, ret])
+        );
+        assert_eq!(
+            Err("Jump to 0004 lands in the middle of an instruction.".into()),
+            verify(&[jump, 0, 1, GetLocal as u8, 0, nil, ret])
+        );
+        assert_eq!(
+            Err("Instruction pops more values than are on the stack.".into()),
+            verify(&[pop, nil, ret])
+        );
+        assert_eq!(
+            Err("Execution runs off the end of the chunk.".into()),
+            verify(&[nil])
+        );
+        // Only one path pushes a value before they meet
+        assert_eq!(
+            Err("Stack has 3 values on one path here and 2 on another.".into()),
+            verify(&[nil, jump_if_false, 0, 1, nil, nil, ret])
+        );
+
+        let with_parameter = Function {
+            arity: 1,
+            chunk: chunk(&[nil, ret]),
+            ..Function::default()
+        };
+        assert_eq!(
+            "The script can't have parameters or upvalues.",
+            with_parameter.verify().unwrap_err().message
+        );
+    }
+}
@@ -1,81 +1,258 @@
+use std::cell::RefCell;
 use std::cmp::Ordering;
 use std::error::Error;
 use std::fmt::Display;
+use std::io::Write;
 use std::ops::{Div, Mul, Not, Sub};
-use std::rc::Rc;
 
-use fnv::FnvHashMap;
+use fnv::{FnvHashMap, FnvHashSet};
 
 use crate::chunk::Chunk;
 use crate::chunk::OpCode;
+use crate::chunk::{CAPTURE_LOCAL, CAPTURE_WIDE};
+use crate::class::{BoundMethod, Class, Instance};
+use crate::closure::{Closure, Upvalue};
+use crate::compiler::Compiler;
+use crate::error::{CompileError, ErrorReporter, RuntimeError, StackFrame};
+use crate::function::Function;
+use crate::gc::{Gc, Heap, Root, Trace, Tracer};
+use crate::native::{self, Native, NativeFn};
 use crate::object::Object;
-use crate::string::LoxString;
-use crate::value::{print_value, Value};
+use crate::string::{Interned, LoxString};
+use crate::value::Value;
 
-#[derive(Debug, Default)]
-pub struct Vm {
-    chunk: Chunk, // reference?
+const FRAMES_MAX: usize = 64;
+const STACK_MAX: usize = FRAMES_MAX * (u8::MAX as usize + 1);
+
+#[derive(Debug)]
+struct CallFrame {
+    closure: Gc<Closure>,
     ip: usize,
+    slot_base: usize, // index of the frame's first slot in `Vm::stack`
+}
+
+pub struct Vm {
+    frames: Vec<CallFrame>,
     stack: Vec<Value>,
-    strings: FnvHashMap<Rc<String>, LoxString>,
-    globals: FnvHashMap<Rc<String>, Value>,
+    heap: Heap,
+    strings: FnvHashSet<Interned>, // weak: doesn't keep strings alive
+    globals: FnvHashMap<Gc<LoxString>, Value>,
+    open_upvalues: Vec<Gc<RefCell<Upvalue>>>, // sorted by stack slot
+    init_string: Gc<LoxString>,
+    compiler_roots: Vec<Value>, // objects referenced by code that's still being compiled
+    trace_execution: bool,      // print each instruction and the stack as we go
+    print_code: bool,           // disassemble each function once it's compiled
+    args: Vec<String>,          // command-line arguments for the script
+    output: Box<dyn Write>,     // where `print` writes to
+    error_reporter: Option<Box<dyn ErrorReporter>>,
+}
+
+// The heap can't be printed usefully and neither can the output or reporter
+impl std::fmt::Debug for Vm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Vm")
+            .field("frames", &self.frames)
+            .field("stack", &self.stack)
+            .field("heap", &self.heap)
+            .field("globals", &self.globals)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Vm {
     pub fn new() -> Self {
-        Self {
-            stack: Vec::with_capacity(256),
-            ..Default::default()
+        Self::with_output(std::io::stdout())
+    }
+
+    // Creates a VM whose `print` statements write to `output` rather than stdout
+    pub fn with_output(output: impl Write + 'static) -> Self {
+        let mut heap = Heap::default();
+        let init_string = heap.alloc(LoxString::from("init".to_string()));
+
+        let mut strings = FnvHashSet::default();
+        strings.insert(Interned(init_string));
+
+        let mut vm = Self {
+            frames: Vec::with_capacity(FRAMES_MAX),
+            stack: Vec::with_capacity(STACK_MAX),
+            heap,
+            strings,
+            globals: Default::default(),
+            open_upvalues: Vec::new(),
+            init_string,
+            compiler_roots: Vec::new(),
+            trace_execution: false,
+            print_code: false,
+            args: Vec::new(),
+            output: Box::new(output),
+            error_reporter: None,
+        };
+
+        native::define_builtins(&mut vm);
+        vm
+    }
+
+    // Makes a Rust function callable from Lox as a global named `name`
+    pub fn define_native(
+        &mut self,
+        name: &str,
+        arity: usize,
+        function: impl Fn(&mut Vm, &[Root<Value>]) -> Result<Root<Value>, String> + 'static,
+    ) {
+        // Both the name and the native are kept on the stack until they're
+        // stored in `globals` so that they can't be collected in the meantime
+        let name = self.intern_string(name.to_string());
+        self.stack.push(Value::Obj(Object::Str(name)));
+
+        let function: Box<NativeFn> = Box::new(function);
+        let native = self.alloc(Native::new(name, arity, function));
+        self.stack.push(Value::Obj(Object::Native(native)));
+
+        self.globals.insert(name, *self.peek(0));
+        self.pop();
+        self.pop();
+    }
+
+    // Collect garbage before every allocation. This is slow but it flushes
+    // out objects that we forgot to keep reachable.
+    pub fn set_gc_stress(&mut self, enabled: bool) {
+        self.heap.set_stress(enabled);
+    }
+
+    pub fn set_trace_execution(&mut self, enabled: bool) {
+        self.trace_execution = enabled;
+    }
+
+    pub fn set_print_code(&mut self, enabled: bool) {
+        self.print_code = enabled;
+    }
+
+    pub(crate) fn print_code(&self) -> bool {
+        self.print_code
+    }
+
+    // Where `print` writes to, for anything else that should go the same way,
+    // e.g. listings
+    pub fn output(&mut self) -> &mut dyn Write {
+        &mut *self.output
+    }
+
+    // Arguments that scripts can read with the `argc()` and `arg(n)` natives
+    pub fn set_args(&mut self, args: Vec<String>) {
+        self.args = args;
+    }
+
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    // Errors from `interpret_source` and `interpret` are passed to `reporter`
+    // as well as being returned
+    pub fn set_error_reporter(&mut self, reporter: impl ErrorReporter + 'static) {
+        self.error_reporter = Some(Box::new(reporter));
+    }
+
+    // Compiles and runs `source`. Globals defined by earlier calls are still
+    // visible so this can be called repeatedly, e.g. by a REPL.
+    pub fn interpret_source(&mut self, source: &str) -> InterpretResult {
+        let function = Compiler::compile(self, source).inspect_err(|error| self.report(error))?;
+        self.interpret(function)
+    }
+
+    // Like `interpret_source` but prints the value of a trailing expression,
+    // see `Compiler::compile_repl`
+    pub fn interpret_repl(&mut self, source: &str) -> InterpretResult {
+        let function =
+            Compiler::compile_repl(self, source).inspect_err(|error| self.report(error))?;
+        self.interpret(function)
+    }
+
+    // `function` has to have come from this VM. Its bytecode is trusted to be
+    // valid: the compiler only emits valid code, and the assembler and the
+    // bytecode loader check theirs with `Function::verify`. Opcodes the VM
+    // doesn't know are still a runtime error rather than a panic.
+    pub fn interpret(&mut self, function: Root<Gc<Function>>) -> InterpretResult {
+        let result = self.interpret_function(function.value_for(&self.heap));
+        if let Err(error) = &result {
+            self.report(error);
         }
+
+        result
     }
 
-    pub fn interpret(&mut self, chunk: Chunk) -> InterpretResult {
-        self.chunk = chunk;
-        self.ip = 0;
-        self.run()
+    fn interpret_function(&mut self, function: Gc<Function>) -> InterpretResult {
+        // Keep the function on the stack while we allocate its closure
+        self.stack.push(Value::Obj(Object::Function(function)));
+        let closure = self.alloc(Closure::new(function, Vec::new()));
+        self.pop();
+
+        self.stack.push(Value::Obj(Object::Closure(closure)));
+        let result = self.call(closure, 0).and_then(|()| self.run());
+        if result.is_err() {
+            self.reset_stack();
+        }
+
+        result
     }
 
-    pub fn run(&mut self) -> InterpretResult {
+    fn run(&mut self) -> InterpretResult {
         loop {
-            #[cfg(debug_assertions)]
-            {
-                self.chunk.disassemble_instruction(self.ip);
-                self.debug_trace_execution();
+            if self.trace_execution {
+                self.debug_trace_execution()?;
             }
 
-            let instruction: OpCode = self.read_byte().try_into().unwrap();
+            let instruction = self.read_opcode()?;
 
             match instruction {
                 OpCode::Return => {
-                    return Ok(());
+                    let result = self.pop();
+                    let frame = self.frames.pop().unwrap();
+                    self.close_upvalues(frame.slot_base);
+
+                    if self.frames.is_empty() {
+                        // Pop the top-level script function
+                        self.pop();
+                        return Ok(());
+                    }
+
+                    // Discard the callee's arguments and locals
+                    self.stack.truncate(frame.slot_base);
+                    self.stack.push(result);
                 }
                 OpCode::Constant => {
-                    let constant = self.read_constant().clone();
+                    let constant = self.read_constant();
+                    self.stack.push(constant);
+                }
+                OpCode::ConstantLong => {
+                    let constant = self.read_constant_long();
                     self.stack.push(constant);
                 }
                 OpCode::Negate => match self.stack.last_mut().unwrap() {
                     Value::Number(value) => *value = -*value,
                     _ => {
-                        self.runtime_error("Operand must be a number.");
-                        return Err(VmError::RuntimeError);
+                        return Err(self.runtime_error("Operand must be a number."));
                     }
                 },
-                OpCode::Add => match (self.pop(), self.pop()) {
-                    (Value::Number(b), Value::Number(a)) => self.stack.push(Value::Number(a + b)),
-                    (Value::Obj(b), Value::Obj(a)) => {
-                        match (b.as_ref(), a.as_ref()) {
-                            (Object::Str(b), Object::Str(a)) => self.concatenate(a, b),
-                            _ => {
-                                // Two objects but at least one wasn't a string
-                                self.runtime_error("Operands must be two numbers or two strings.");
-                                return Err(VmError::RuntimeError);
-                            }
-                        };
+                OpCode::Add => match (*self.peek(1), *self.peek(0)) {
+                    (Value::Number(a), Value::Number(b)) => {
+                        self.pop();
+                        self.pop();
+                        self.stack.push(Value::Number(a + b));
+                    }
+                    (Value::Obj(Object::Str(a)), Value::Obj(Object::Str(b))) => {
+                        self.concatenate(a, b)
                     }
                     _ => {
                         // At least one value wasn't a number nor a string
-                        self.runtime_error("Operands must be two numbers or two strings.");
-                        return Err(VmError::RuntimeError);
+                        return Err(
+                            self.runtime_error("Operands must be two numbers or two strings.")
+                        );
                     }
                 },
                 OpCode::Subtract => self.numeric_binary_op(Sub::sub)?,
@@ -96,103 +273,546 @@ impl Vm {
                 OpCode::Greater => self.comparison_binary_op(Ordering::Greater)?,
                 OpCode::Less => self.comparison_binary_op(Ordering::Less)?,
                 OpCode::Print => {
-                    print_value(&self.pop());
-                    println!();
+                    let value = self.pop();
+                    if let Err(error) = writeln!(self.output, "{value}") {
+                        return Err(self.runtime_error(format!("Couldn't print: {error}.")));
+                    }
                 }
                 OpCode::Pop => {
                     self.pop();
                 }
                 OpCode::DefineGlobal => {
-                    let name = self.read_string().string();
-                    let value = self.peek(0);
-                    self.globals.insert(name, value.clone());
-
-                    // We don't pop the value until after we've added it to
-                    // `globals` so that the VM can still find it in the event
-                    // that a GC run is triggered in the middle of adding the
-                    // value to `globals`.
-                    let _value = self.pop();
+                    let name = self.read_string();
+                    self.define_global(name);
+                }
+                OpCode::DefineGlobalLong => {
+                    let name = self.read_constant_long().as_string();
+                    self.define_global(name);
                 }
                 OpCode::GetGlobal => {
-                    let name = self.read_string().string();
-                    match self.globals.get(&name) {
-                        Some(value) => self.stack.push(value.clone()),
-                        None => {
-                            self.runtime_error(format!("Undefined variable '{}'.", *name));
-                            return Err(VmError::RuntimeError);
-                        }
-                    }
+                    let name = self.read_string();
+                    self.get_global_by_name(name)?;
+                }
+                OpCode::GetGlobalLong => {
+                    let name = self.read_constant_long().as_string();
+                    self.get_global_by_name(name)?;
                 }
                 OpCode::SetGlobal => {
-                    let name = self.read_string().string();
-                    let value = self.peek(0).clone();
-                    if self.globals.insert(name.clone(), value).is_none() {
-                        self.globals.remove(&name);
-                        self.pop();
-                        self.runtime_error(format!("Undefined variable '{}'.", name));
-                        return Err(VmError::RuntimeError);
-                    }
+                    let name = self.read_string();
+                    self.set_global_by_name(name)?;
+                }
+                OpCode::SetGlobalLong => {
+                    let name = self.read_constant_long().as_string();
+                    self.set_global_by_name(name)?;
                 }
                 OpCode::GetLocal => {
-                    let slot = self.read_byte();
-                    let value = self.stack[slot as usize].clone();
+                    let slot = self.read_byte() as usize;
+                    let value = self.stack[self.frame().slot_base + slot];
                     self.stack.push(value);
                 }
                 OpCode::SetLocal => {
-                    let slot = self.read_byte();
-                    let value = self.peek(0).clone();
-                    self.stack[slot as usize] = value;
+                    let slot = self.read_byte() as usize;
+                    let value = *self.peek(0);
+                    let slot_base = self.frame().slot_base;
+                    self.stack[slot_base + slot] = value;
                 }
                 OpCode::JumpIfFalse => {
                     let offset = self.read_short();
                     if self.peek(0).is_falsey() {
-                        self.ip += offset as usize;
+                        self.frame_mut().ip += offset as usize;
                     }
                 }
                 OpCode::Jump => {
-                    self.ip += self.read_short() as usize;
+                    let offset = self.read_short();
+                    self.frame_mut().ip += offset as usize;
                 }
                 OpCode::Loop => {
-                    self.ip -= self.read_short() as usize;
+                    let offset = self.read_short();
+                    self.frame_mut().ip -= offset as usize;
+                }
+                OpCode::Wide => {
+                    // Same as the instruction that follows but with an
+                    // operand twice the size
+                    let instruction = self.read_opcode()?;
+                    match instruction {
+                        OpCode::GetLocal => {
+                            let slot = self.read_short() as usize;
+                            let value = self.stack[self.frame().slot_base + slot];
+                            self.stack.push(value);
+                        }
+                        OpCode::SetLocal => {
+                            let slot = self.read_short() as usize;
+                            let value = *self.peek(0);
+                            let slot_base = self.frame().slot_base;
+                            self.stack[slot_base + slot] = value;
+                        }
+                        OpCode::JumpIfFalse => {
+                            let offset = self.read_u32();
+                            if self.peek(0).is_falsey() {
+                                self.frame_mut().ip += offset as usize;
+                            }
+                        }
+                        OpCode::Jump => {
+                            let offset = self.read_u32();
+                            self.frame_mut().ip += offset as usize;
+                        }
+                        OpCode::Loop => {
+                            let offset = self.read_u32();
+                            self.frame_mut().ip -= offset as usize;
+                        }
+                        _ => {
+                            return Err(self.runtime_error(format!(
+                                "{} has no wide form.",
+                                instruction.name()
+                            )))
+                        }
+                    }
+                }
+                OpCode::Call => {
+                    let arg_count = self.read_byte();
+                    let callee = *self.peek(arg_count as usize);
+                    self.call_value(callee, arg_count)?;
+                }
+                OpCode::Closure | OpCode::ClosureLong => {
+                    let function = self
+                        .read_constant_operand(instruction)
+                        .as_object()
+                        .as_function();
+                    let mut upvalues = Vec::with_capacity(function.upvalue_count);
+
+                    for _ in 0..function.upvalue_count {
+                        let flags = self.read_byte();
+                        let is_local = flags & CAPTURE_LOCAL != 0;
+                        let index = if flags & CAPTURE_WIDE != 0 {
+                            self.read_short() as usize
+                        } else {
+                            self.read_byte() as usize
+                        };
+
+                        let upvalue = if is_local {
+                            self.capture_upvalue(self.frame().slot_base + index)
+                        } else {
+                            self.frame().closure.upvalues[index]
+                        };
+                        upvalues.push(upvalue);
+                    }
+
+                    let closure = self.alloc(Closure::new(function, upvalues));
+                    self.stack.push(Value::Obj(Object::Closure(closure)));
+                }
+                OpCode::GetUpvalue => {
+                    let slot = self.read_byte() as usize;
+                    let upvalue = self.frame().closure.upvalues[slot];
+                    let value = match &*upvalue.borrow() {
+                        Upvalue::Open(stack_slot) => self.stack[*stack_slot],
+                        Upvalue::Closed(value) => *value,
+                    };
+                    self.stack.push(value);
+                }
+                OpCode::SetUpvalue => {
+                    let slot = self.read_byte() as usize;
+                    let value = *self.peek(0);
+                    let upvalue = self.frame().closure.upvalues[slot];
+                    match &mut *upvalue.borrow_mut() {
+                        Upvalue::Open(stack_slot) => self.stack[*stack_slot] = value,
+                        Upvalue::Closed(closed) => *closed = value,
+                    };
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                }
+                OpCode::Class | OpCode::ClassLong => {
+                    let name = self.read_constant_operand(instruction).as_string();
+                    let class = self.alloc(Class::new(name));
+                    self.stack.push(Value::Obj(Object::Class(class)));
+                }
+                OpCode::GetProperty | OpCode::GetPropertyLong => {
+                    let name = self.read_constant_operand(instruction).as_string();
+                    let instance = match self.peek(0).as_instance() {
+                        Some(instance) => instance,
+                        None => {
+                            return Err(self.runtime_error("Only instances have properties."));
+                        }
+                    };
+
+                    // Fields shadow methods
+                    let field = instance.fields.borrow().get(&name).copied();
+                    match field {
+                        Some(value) => {
+                            self.pop(); // the instance
+                            self.stack.push(value);
+                        }
+                        None => self.bind_method(instance.class, name)?,
+                    }
+                }
+                OpCode::SetProperty | OpCode::SetPropertyLong => {
+                    let name = self.read_constant_operand(instruction).as_string();
+                    let instance = match self.peek(1).as_instance() {
+                        Some(instance) => instance,
+                        None => {
+                            return Err(self.runtime_error("Only instances have fields."));
+                        }
+                    };
+
+                    instance.fields.borrow_mut().insert(name, *self.peek(0));
+
+                    // Remove the instance but leave the value as the result
+                    let value = self.pop();
+                    self.pop();
+                    self.stack.push(value);
+                }
+                OpCode::Method | OpCode::MethodLong => {
+                    let name = self.read_constant_operand(instruction).as_string();
+
+                    // The compiler never gets these wrong but hand-written
+                    // bytecode might, and the verifier doesn't track types
+                    let (Value::Obj(Object::Closure(method)), Value::Obj(Object::Class(class))) =
+                        (*self.peek(0), *self.peek(1))
+                    else {
+                        return Err(self.runtime_error("Only classes can have methods."));
+                    };
+                    class.methods.borrow_mut().insert(name, method);
+                    self.pop(); // the method
+                }
+                OpCode::Invoke | OpCode::InvokeLong => {
+                    let name = self.read_constant_operand(instruction).as_string();
+                    let arg_count = self.read_byte();
+                    self.invoke(name, arg_count)?;
+                }
+                OpCode::Inherit => {
+                    let superclass = match self.peek(1) {
+                        Value::Obj(Object::Class(class)) => *class,
+                        _ => {
+                            return Err(self.runtime_error("Superclass must be a class."));
+                        }
+                    };
+
+                    // Copy the inherited methods down into the subclass. Methods
+                    // that the subclass defines itself will overwrite these.
+                    let Value::Obj(Object::Class(subclass)) = *self.peek(0) else {
+                        return Err(self.runtime_error("Only classes can inherit."));
+                    };
+                    subclass
+                        .methods
+                        .borrow_mut()
+                        .extend(superclass.methods.borrow().clone());
+                    self.pop(); // the subclass
+                }
+                OpCode::GetSuper | OpCode::GetSuperLong => {
+                    let name = self.read_constant_operand(instruction).as_string();
+                    let superclass = self.pop_superclass()?;
+                    self.bind_method(superclass, name)?;
+                }
+                OpCode::SuperInvoke | OpCode::SuperInvokeLong => {
+                    let name = self.read_constant_operand(instruction).as_string();
+                    let arg_count = self.read_byte();
+                    let superclass = self.pop_superclass()?;
+                    self.invoke_from_class(superclass, name, arg_count)?;
                 }
             }
         }
     }
 
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().unwrap()
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().unwrap()
+    }
+
+    fn chunk(&self) -> &Chunk {
+        &self.frame().closure.function.chunk
+    }
+
     fn read_byte(&mut self) -> u8 {
-        let byte = self.chunk.code[self.ip];
-        self.ip += 1;
+        let frame = self.frame_mut();
+        let byte = frame.closure.function.chunk.code[frame.ip];
+        frame.ip += 1;
         byte
     }
 
+    fn read_opcode(&mut self) -> Result<OpCode, VmError> {
+        let byte = self.read_byte();
+        OpCode::try_from(byte).map_err(|_| self.runtime_error(format!("Unknown opcode {byte}.")))
+    }
+
     fn read_short(&mut self) -> u16 {
-        let top = self.chunk.code[self.ip] as u16;
-        let bottom = self.chunk.code[self.ip + 1] as u16;
-        self.ip += 2;
+        let top = self.read_byte() as u16;
+        let bottom = self.read_byte() as u16;
         (top << 8) | bottom
     }
 
-    fn read_constant(&mut self) -> &Value {
+    fn read_u32(&mut self) -> u32 {
+        let top = self.read_short() as u32;
+        let bottom = self.read_short() as u32;
+        (top << 16) | bottom
+    }
+
+    fn read_constant(&mut self) -> Value {
         let byte = self.read_byte();
-        &self.chunk.constants[byte as usize]
+        self.chunk().constants[byte as usize]
     }
 
-    fn read_string(&mut self) -> &LoxString {
+    // Reads a 24-bit constant index, as used by the `*Long` instructions
+    fn read_constant_long(&mut self) -> Value {
+        let top = self.read_byte() as usize;
+        let index = (top << 16) | self.read_short() as usize;
+        self.chunk().constants[index]
+    }
+
+    // Reads the constant index of an instruction that comes in both widths
+    fn read_constant_operand(&mut self, instruction: OpCode) -> Value {
+        match instruction.short_form() {
+            Some(_) => self.read_constant_long(),
+            None => self.read_constant(),
+        }
+    }
+
+    fn define_global(&mut self, name: Gc<LoxString>) {
+        let value = *self.peek(0);
+        self.globals.insert(name, value);
+
+        // We don't pop the value until after we've added it to
+        // `globals` so that the VM can still find it in the event
+        // that a GC run is triggered in the middle of adding the
+        // value to `globals`.
+        let _value = self.pop();
+    }
+
+    fn get_global_by_name(&mut self, name: Gc<LoxString>) -> Result<(), VmError> {
+        match self.globals.get(&name) {
+            Some(value) => {
+                self.stack.push(*value);
+                Ok(())
+            }
+            None => Err(self.runtime_error(format!("Undefined variable '{}'.", name.as_str()))),
+        }
+    }
+
+    fn set_global_by_name(&mut self, name: Gc<LoxString>) -> Result<(), VmError> {
+        let value = *self.peek(0);
+        if self.globals.insert(name, value).is_none() {
+            self.globals.remove(&name);
+            self.pop();
+            return Err(self.runtime_error(format!("Undefined variable '{}'.", name.as_str())));
+        }
+
+        Ok(())
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: u8) -> Result<(), VmError> {
+        // The slot holding the callee, right below its arguments
+        let callee_slot = self.stack.len() - arg_count as usize - 1;
+
+        if let Value::Obj(object) = callee {
+            match object {
+                Object::Closure(closure) => return self.call(closure, arg_count),
+                Object::Class(class) => {
+                    // Replace the class with the new instance so that it
+                    // becomes `this` in the initializer
+                    let instance = self.alloc(Instance::new(class));
+                    self.stack[callee_slot] = Value::Obj(Object::Instance(instance));
+
+                    if let Some(initializer) = class.find_method(self.init_string) {
+                        return self.call(initializer, arg_count);
+                    } else if arg_count != 0 {
+                        return Err(self
+                            .runtime_error(format!("Expected 0 arguments but got {arg_count}.")));
+                    }
+
+                    return Ok(());
+                }
+                Object::BoundMethod(bound_method) => {
+                    self.stack[callee_slot] = bound_method.receiver;
+                    return self.call(bound_method.method, arg_count);
+                }
+                Object::Native(native) => return self.call_native(native, arg_count),
+                _ => {}
+            }
+        }
+
+        Err(self.runtime_error("Can only call functions and classes."))
+    }
+
+    // For `super` expressions, which leave the superclass on top of the stack
+    fn pop_superclass(&mut self) -> Result<Gc<Class>, VmError> {
+        match self.pop() {
+            Value::Obj(Object::Class(superclass)) => Ok(superclass),
+            _ => Err(self.runtime_error("Superclass must be a class.")),
+        }
+    }
+
+    fn invoke(&mut self, name: Gc<LoxString>, arg_count: u8) -> Result<(), VmError> {
+        let instance = match self.peek(arg_count as usize).as_instance() {
+            Some(instance) => instance,
+            None => {
+                return Err(self.runtime_error("Only instances have methods."));
+            }
+        };
+
+        // A field might hold a function, in which case we call that instead
+        let field = instance.fields.borrow().get(&name).copied();
+        if let Some(value) = field {
+            let callee_slot = self.stack.len() - arg_count as usize - 1;
+            self.stack[callee_slot] = value;
+            return self.call_value(value, arg_count);
+        }
+
+        self.invoke_from_class(instance.class, name, arg_count)
+    }
+
+    fn invoke_from_class(
+        &mut self,
+        class: Gc<Class>,
+        name: Gc<LoxString>,
+        arg_count: u8,
+    ) -> Result<(), VmError> {
+        match class.find_method(name) {
+            Some(method) => self.call(method, arg_count),
+            None => Err(self.runtime_error(format!("Undefined property '{}'.", name.as_str()))),
+        }
+    }
+
+    fn bind_method(&mut self, class: Gc<Class>, name: Gc<LoxString>) -> Result<(), VmError> {
+        let method = match class.find_method(name) {
+            Some(method) => method,
+            None => {
+                return Err(self.runtime_error(format!("Undefined property '{}'.", name.as_str())));
+            }
+        };
+
+        // The instance stays on the stack until the bound method has been
+        // allocated so that it can't be collected in the meantime
+        let bound_method = self.alloc(BoundMethod {
+            receiver: *self.peek(0),
+            method,
+        });
+
+        self.pop(); // the instance
+        self.stack
+            .push(Value::Obj(Object::BoundMethod(bound_method)));
+        Ok(())
+    }
+
+    fn call_native(&mut self, native: Gc<Native>, arg_count: u8) -> Result<(), VmError> {
+        if arg_count as usize != native.arity {
+            return Err(self.runtime_error(format!(
+                "Expected {} arguments but got {}.",
+                native.arity, arg_count
+            )));
+        }
+
+        // The arguments stay on the stack during the call so that the native
+        // can allocate without them being collected
+        let callee_slot = self.stack.len() - arg_count as usize - 1;
+        let args: Vec<_> = self.stack[callee_slot + 1..]
+            .iter()
+            .map(|arg| self.root_value(*arg))
+            .collect();
+
+        match (native.function)(self, &args) {
+            Ok(result) => {
+                self.stack.truncate(callee_slot);
+                self.stack.push(result.value_for(&self.heap));
+                Ok(())
+            }
+            Err(message) => Err(self.runtime_error(message)),
+        }
+    }
+
+    fn call(&mut self, closure: Gc<Closure>, arg_count: u8) -> Result<(), VmError> {
+        if arg_count as usize != closure.function.arity {
+            return Err(self.runtime_error(format!(
+                "Expected {} arguments but got {}.",
+                closure.function.arity, arg_count
+            )));
+        }
+
+        if self.frames.len() == FRAMES_MAX {
+            return Err(self.runtime_error("Stack overflow."));
+        }
+
+        // The callee and its arguments are already on the stack. The callee
+        // itself occupies slot zero of the new frame.
+        let slot_base = self.stack.len() - arg_count as usize - 1;
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            slot_base,
+        });
+
+        Ok(())
+    }
+
+    fn capture_upvalue(&mut self, slot: usize) -> Gc<RefCell<Upvalue>> {
+        let position = self
+            .open_upvalues
+            .binary_search_by_key(&slot, |upvalue| upvalue.borrow().open_slot().unwrap());
+
+        match position {
+            // Reuse the existing upvalue so that every closure sees the same variable
+            Ok(index) => self.open_upvalues[index],
+            Err(index) => {
+                let upvalue = self.alloc(RefCell::new(Upvalue::Open(slot)));
+                self.open_upvalues.insert(index, upvalue);
+                upvalue
+            }
+        }
+    }
+
+    // Closes every open upvalue that refers to `last_slot` or anything above it
+    fn close_upvalues(&mut self, last_slot: usize) {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            let slot = upvalue.borrow().open_slot().unwrap();
+            if slot < last_slot {
+                break;
+            }
+
+            let value = self.stack[slot];
+            *upvalue.borrow_mut() = Upvalue::Closed(value);
+            self.open_upvalues.pop();
+        }
+    }
+
+    fn read_string(&mut self) -> Gc<LoxString> {
         self.read_constant().as_string()
     }
 
     fn reset_stack(&mut self) {
-        self.stack.clear()
+        self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
     }
 
-    fn debug_trace_execution(&self) {
-        print!("          ");
+    // Writes the stack and the next instruction to the output, like `print`
+    fn debug_trace_execution(&mut self) -> Result<(), VmError> {
+        let stack: String = self
+            .stack
+            .iter()
+            .map(|slot| format!("[ {slot} ]"))
+            .collect();
+        let frame = self.frames.last().unwrap();
+        let chunk = &frame.closure.function.chunk;
 
-        for slot in &self.stack {
-            print!("[ {slot} ]");
+        // The disassembler only decodes valid instructions, `run` reports the rest
+        let opcode = |offset: usize| OpCode::try_from(chunk.code[offset]).ok();
+        let valid = match opcode(frame.ip) {
+            Some(OpCode::Wide) => opcode(frame.ip + 1).is_some_and(|op| op.has_wide_form()),
+            opcode => opcode.is_some(),
+        };
+        if !valid {
+            return Ok(());
         }
 
-        println!();
+        let result = writeln!(self.output, "          {stack}")
+            .and_then(|()| chunk.disassemble_instruction(frame.ip, &mut *self.output));
+        if let Err(error) = result {
+            return Err(self.runtime_error(format!("Couldn't print: {error}.")));
+        }
+
+        Ok(())
     }
 
     fn numeric_binary_op(&mut self, op: impl Fn(f64, f64) -> f64) -> Result<(), VmError> {
@@ -201,10 +821,7 @@ impl Vm {
                 self.stack.push(Value::Number(op(a, b)));
                 Ok(())
             }
-            _ => {
-                self.runtime_error("Operands must be numbers.");
-                Err(VmError::RuntimeError)
-            }
+            _ => Err(self.runtime_error("Operands must be numbers.")),
         }
     }
 
@@ -217,10 +834,7 @@ impl Vm {
                 self.stack.push(Value::Bool(o == ordering));
                 Ok(())
             }
-            None => {
-                self.runtime_error("Operands must be numbers.");
-                Err(VmError::RuntimeError)
-            }
+            None => Err(self.runtime_error("Operands must be numbers.")),
         }
     }
 
@@ -232,41 +846,574 @@ impl Vm {
         self.stack.get(self.stack.len() - 1 - distance).unwrap()
     }
 
-    fn concatenate(&mut self, a: &LoxString, b: &LoxString) {
-        let new_object = Object::Str(LoxString::add(self, a, b));
-        let new_value = Value::Obj(Box::new(new_object));
-        self.stack.push(new_value);
+    // Expects both operands to still be on the stack so that they can't be
+    // collected while the result is being allocated
+    fn concatenate(&mut self, a: Gc<LoxString>, b: Gc<LoxString>) {
+        let result = LoxString::add(self, &a, &b);
+        self.pop();
+        self.pop();
+        self.stack.push(Value::Obj(Object::Str(result)));
     }
 
-    pub fn intern_string(&mut self, string: String) -> LoxString {
-        let string = Rc::new(string);
+    // Creates a Lox string, which is kept alive until the root is dropped
+    pub fn new_string(&mut self, string: &str) -> Root<Value> {
+        let string = self.intern_string(string.to_string());
+        self.root_value(Value::Obj(Object::Str(string)))
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<Root<Value>> {
+        let name = self.strings.get(name)?.0;
+        let value = *self.globals.get(&name)?;
+        Some(self.root_value(value))
+    }
+
+    // `value` has to have come from this VM, e.g. from `new_string`, unless
+    // it's a number, a boolean or nil
+    pub fn set_global(&mut self, name: &str, value: &Root<Value>) {
+        // The root keeps `value` alive while the name is allocated
+        let value = value.value_for(&self.heap);
+        let name = self.intern_string(name.to_string());
+        self.globals.insert(name, value);
+    }
 
-        self.strings
-            .entry(string.clone())
-            .or_insert_with(|| LoxString::from(string))
-            .clone()
+    // Every global variable, in no particular order
+    pub fn globals(&self) -> impl Iterator<Item = (&str, Root<Value>)> + '_ {
+        self.globals
+            .iter()
+            .map(|(name, value)| (name.as_str(), self.root_value(*value)))
     }
 
-    fn runtime_error(&self, message: impl AsRef<str>) {
-        eprintln!("{}", message.as_ref());
+    pub fn interned_string_count(&self) -> usize {
+        self.strings.len()
+    }
+
+    // The values on the stack, bottom first. This is empty between calls to
+    // `interpret` unless something has gone wrong.
+    pub fn stack(&self) -> Vec<Root<Value>> {
+        self.stack
+            .iter()
+            .map(|value| self.root_value(*value))
+            .collect()
+    }
 
-        let line = self.chunk.lines[self.ip - 1];
-        eprintln!("[line {line}] in script");
+    // Forgets every global, including natives added with `define_native`, and
+    // puts the builtins back. Settings such as the output are kept.
+    pub fn reset(&mut self) {
+        self.reset_stack();
+        self.globals.clear();
+        native::define_builtins(self);
+        self.collect_garbage();
+    }
+
+    pub(crate) fn intern_string(&mut self, string: String) -> Gc<LoxString> {
+        if let Some(interned) = self.strings.get(string.as_str()) {
+            return interned.0;
+        }
+
+        let lox_string = self.alloc(LoxString::from(string));
+        self.strings.insert(Interned(lox_string));
+        lox_string
+    }
+
+    // Moves `value` onto the heap, first collecting garbage if it's time to.
+    // Any objects that `value` refers to have to be reachable from a root.
+    pub(crate) fn alloc<T: Trace + 'static>(&mut self, value: T) -> Gc<T> {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+
+        self.heap.alloc(value)
+    }
+
+    // Keeps `value` alive while the compiler still needs it, since the
+    // function it belongs to isn't reachable from anywhere else yet.
+    pub(crate) fn push_compiler_root(&mut self, value: Value) {
+        self.compiler_roots.push(value);
+    }
+
+    pub(crate) fn clear_compiler_roots(&mut self) {
+        self.compiler_roots.clear();
+    }
+
+    // Keeps `value` alive until the returned root is dropped, for handing
+    // it to code outside the VM
+    pub(crate) fn root_value(&self, value: Value) -> Root<Value> {
+        Root::new(&self.heap, value, value)
+    }
+
+    pub(crate) fn root_function(&self, function: Gc<Function>) -> Root<Gc<Function>> {
+        Root::new(&self.heap, function, Value::Obj(Object::Function(function)))
+    }
+
+    pub fn collect_garbage(&mut self) {
+        let mut tracer = Tracer::default();
+        self.mark_roots(&mut tracer);
+        tracer.trace_references();
+
+        // Drop interned strings that are about to be freed
+        self.strings.retain(|string| string.0.is_marked());
+
+        self.heap.sweep();
+    }
+
+    fn mark_roots(&self, tracer: &mut Tracer) {
+        for value in &self.stack {
+            tracer.mark_value(value);
+        }
+
+        for frame in &self.frames {
+            tracer.mark(frame.closure);
+        }
+
+        for upvalue in &self.open_upvalues {
+            tracer.mark(*upvalue);
+        }
+
+        for (name, value) in &self.globals {
+            tracer.mark(*name);
+            tracer.mark_value(value);
+        }
+
+        for value in &self.compiler_roots {
+            tracer.mark_value(value);
+        }
+
+        self.heap.mark_pinned(tracer);
+
+        tracer.mark(self.init_string);
+    }
+
+    fn report(&mut self, error: &VmError) {
+        let Some(reporter) = self.error_reporter.as_mut() else {
+            return;
+        };
+
+        match error {
+            VmError::CompileError(errors) => reporter.compile_errors(errors),
+            VmError::RuntimeError(error) => reporter.runtime_error(error),
+        }
+    }
+
+    // Builds an error describing where each active call was when things went
+    // wrong. The stack is left alone; `interpret` resets it afterwards.
+    fn runtime_error(&self, message: impl Into<String>) -> VmError {
+        let stack_trace: Vec<StackFrame> = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                let function = &frame.closure.function;
+                StackFrame {
+                    function: function.display_name(),
+                    line: function.chunk.line_at(frame.ip - 1),
+                }
+            })
+            .collect();
+
+        // There's no frame if calling the script itself failed
+        let (line, snippet) = match self.frames.last() {
+            Some(frame) => (
+                stack_trace[0].line,
+                frame.closure.function.chunk.snippet(frame.ip - 1),
+            ),
+            None => (0, None),
+        };
+
+        VmError::RuntimeError(RuntimeError {
+            message: message.into(),
+            line,
+            snippet,
+            stack_trace,
+        })
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum VmError {
-    CompileError,
-    RuntimeError,
+    CompileError(Vec<CompileError>),
+    RuntimeError(RuntimeError),
 }
 
 impl Display for VmError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            VmError::CompileError(errors) => {
+                for (i, error) in errors.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{error}")?;
+                }
+                Ok(())
+            }
+            VmError::RuntimeError(error) => write!(f, "{error}"),
+        }
     }
 }
 
 impl Error for VmError {}
 
 pub type InterpretResult = Result<(), VmError>;
+
+mod tests {
+    #[allow(unused)]
+    use super::*;
+
+    #[test]
+    fn compile_errors_have_locations() {
+        let mut vm = Vm::new();
+        let Err(VmError::CompileError(errors)) = vm.interpret_source("var x = 1;\n  print ;")
+        else {
+            panic!("expected a compile error");
+        };
+
+        assert_eq!(1, errors.len());
+        assert_eq!((2, 9), (errors[0].line, errors[0].column));
+        assert_eq!(";", errors[0].lexeme);
+        assert_eq!("Expect expression.", errors[0].message);
+    }
+
+    #[test]
+    fn compile_errors_are_all_reported() {
+        let mut vm = Vm::new();
+        let Err(VmError::CompileError(errors)) = vm.interpret_source("print ;\nvar = 1;\nprint 1")
+        else {
+            panic!("expected a compile error");
+        };
+        let lines: Vec<_> = errors.iter().map(|error| error.line).collect();
+        assert_eq!(vec![1, 2, 3], lines);
+
+        // Including inside blocks
+        for source in [
+            "print ;\n".repeat(100),
+            format!("{{\n{}}}", "print ;\n".repeat(100)),
+        ] {
+            let Err(VmError::CompileError(errors)) = vm.interpret_source(&source) else {
+                panic!("expected a compile error");
+            };
+            assert_eq!(crate::compiler::MAX_COMPILE_ERRORS, errors.len());
+        }
+    }
+
+    #[test]
+    fn more_than_256_constants() {
+        let mut vm = Vm::new();
+        let mut source: String = (0..300).map(|i| format!("var v{i} = {i}.5;\n")).collect();
+        source.push_str("v299 = v299 + v0;");
+        vm.interpret_source(&source).unwrap();
+
+        assert_eq!(Some(300.0), vm.get_global("v299").unwrap().as_number());
+    }
+
+    #[test]
+    fn more_than_256_locals() {
+        let mut vm = Vm::new();
+        let mut source: String = (0..300).map(|i| format!("var v{i} = {i};\n")).collect();
+        source = format!("{{\n{source}v299 = v299 + v0 + 1;\nresult = v299;\n}}");
+        vm.set_global("result", &Root::from(()));
+        vm.interpret_source(&source).unwrap();
+
+        assert_eq!(Some(300.0), vm.get_global("result").unwrap().as_number());
+    }
+
+    #[test]
+    fn closures_capture_locals_past_256() {
+        let mut vm = Vm::new();
+        let mut source: String = (0..300).map(|i| format!("var v{i} = {i};\n")).collect();
+        source = format!(
+            "{{\n{source}fun get() {{ return v299 + v0; }}\nv299 = 1000;\nresult = get;\n}}\nresult = result();"
+        );
+        vm.set_global("result", &Root::from(()));
+        vm.interpret_source(&source).unwrap();
+
+        assert_eq!(Some(1000.0), vm.get_global("result").unwrap().as_number());
+    }
+
+    #[test]
+    fn jumps_over_more_than_64k_of_code() {
+        let mut vm = Vm::new();
+        let body = "x = x + 1;\n".repeat(10_000);
+        let source = format!(
+            "var x = 0;\nvar i = 0;\nwhile (i < 2) {{\nif (i == 1) {{\n{body}}}\ni = i + 1;\n}}"
+        );
+        vm.interpret_source(&source).unwrap();
+
+        assert_eq!(Some(10_000.0), vm.get_global("x").unwrap().as_number());
+    }
+
+    #[test]
+    fn script_args_are_readable() {
+        let mut vm = Vm::new();
+        vm.set_args(vec!["first".to_string(), "second".to_string()]);
+        vm.interpret_source("var n = argc(); var last = arg(n - 1); var missing = arg(n);")
+            .unwrap();
+
+        assert_eq!(Some(2.0), vm.get_global("n").unwrap().as_number());
+        assert_eq!(Some("second"), vm.get_global("last").unwrap().as_str());
+        assert!(vm.get_global("missing").unwrap().is_nil());
+    }
+
+    // Collects output or error messages so that tests can check them
+    #[allow(unused)]
+    #[derive(Clone, Default)]
+    struct Captured(std::rc::Rc<RefCell<Vec<String>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let text = String::from_utf8_lossy(buf).to_string();
+            self.0.borrow_mut().push(text);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl ErrorReporter for Captured {
+        fn compile_errors(&mut self, errors: &[CompileError]) {
+            for error in errors {
+                self.0.borrow_mut().push(error.message.clone());
+            }
+        }
+
+        fn runtime_error(&mut self, error: &RuntimeError) {
+            self.0.borrow_mut().push(error.message.clone());
+        }
+    }
+
+    #[test]
+    fn output_and_errors_can_be_captured() {
+        let output = Captured::default();
+        let errors = Captured::default();
+        let mut vm = Vm::with_output(output.clone());
+        vm.set_error_reporter(errors.clone());
+
+        vm.interpret_source("print 1 + 2;").unwrap();
+        assert!(vm.interpret_source("print ;").is_err());
+        assert!(vm.interpret_source("print -nil;").is_err());
+
+        assert_eq!("3\n", output.0.borrow().concat());
+        assert_eq!(
+            vec!["Expect expression.", "Operand must be a number."],
+            *errors.0.borrow()
+        );
+    }
+
+    #[test]
+    fn trace_goes_to_the_output() {
+        let output = Captured::default();
+        let mut vm = Vm::with_output(output.clone());
+        vm.set_trace_execution(true);
+        vm.interpret_source("print 1;").unwrap();
+
+        assert_eq!(
+            "          [ <script> ]\n\
+             0000    1 OP_CONSTANT         0 1\n\
+             \x20         [ <script> ][ 1 ]\n\
+             0002    | OP_PRINT\n\
+             1\n\
+             \x20         [ <script> ]\n\
+             0003    | OP_NIL\n\
+             \x20         [ <script> ][ nil ]\n\
+             0004    | OP_RETURN\n",
+            output.0.borrow().concat()
+        );
+    }
+
+    #[test]
+    fn listings_go_to_the_output() {
+        let output = Captured::default();
+        let mut vm = Vm::with_output(output.clone());
+        vm.set_print_code(true);
+        vm.interpret_source("print 1;").unwrap();
+
+        assert_eq!(
+            "== <script> ==\n\
+             0000    1 OP_CONSTANT         0 1\n\
+             0002    | OP_PRINT\n\
+             0003    | OP_NIL\n\
+             0004    | OP_RETURN\n\
+             \n\
+             1\n",
+            output.0.borrow().concat()
+        );
+    }
+
+    #[test]
+    fn repl_prints_trailing_expression() {
+        let output = Captured::default();
+        let mut vm = Vm::with_output(output.clone());
+
+        vm.interpret_repl("var x = 2;").unwrap();
+        vm.interpret_repl("x * 3").unwrap();
+        vm.interpret_repl("x + 1; x - 1;").unwrap();
+        vm.interpret_repl("{ x; }").unwrap();
+        assert!(vm.interpret_source("x * 3").is_err());
+
+        assert_eq!("6\n1\n", output.0.borrow().concat());
+    }
+
+    #[test]
+    fn badly_typed_bytecode_is_a_runtime_error() {
+        let mut vm = Vm::new();
+        let listings = [
+            "OP_NIL\nOP_NIL\nOP_METHOD \"x\"\nOP_RETURN",
+            "OP_CLASS \"A\"\nOP_NIL\nOP_INHERIT\nOP_RETURN",
+            "OP_NIL\nOP_NIL\nOP_GET_SUPER \"x\"\nOP_RETURN",
+            "OP_NIL\nOP_NIL\nOP_SUPER_INVOKE (0 args) \"x\"\nOP_RETURN",
+        ];
+
+        for listing in listings {
+            let script = crate::Assembler::assemble(&mut vm, listing).unwrap();
+            assert!(matches!(
+                vm.interpret(script),
+                Err(VmError::RuntimeError(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn unknown_opcodes_are_a_runtime_error() {
+        let mut vm = Vm::new();
+        let cases = [
+            (vec![0xff], "Unknown opcode 255."),
+            (
+                vec![OpCode::Wide.into(), OpCode::Add.into()],
+                "OP_ADD has no wide form.",
+            ),
+        ];
+
+        for (code, message) in cases {
+            let mut function = Function::new(None);
+            for byte in code {
+                function.chunk.write_byte(byte, 1, Default::default());
+            }
+            let function = vm.alloc(function);
+            let script = vm.root_function(function);
+
+            // Tracing mustn't trip over them either
+            vm.set_trace_execution(true);
+            let Err(VmError::RuntimeError(error)) = vm.interpret(script) else {
+                panic!("expected a runtime error");
+            };
+            assert_eq!(message, error.message);
+            assert!(vm.stack().is_empty());
+        }
+    }
+
+    #[test]
+    fn scripts_with_parameters_are_a_runtime_error() {
+        let mut vm = Vm::new();
+        let mut function = Function::new(None);
+        function.arity = 1;
+        function
+            .chunk
+            .write_opcode(OpCode::Nil, 1, Default::default());
+        function
+            .chunk
+            .write_opcode(OpCode::Return, 1, Default::default());
+        let function = vm.alloc(function);
+        let script = vm.root_function(function);
+
+        let Err(VmError::RuntimeError(error)) = vm.interpret(script) else {
+            panic!("expected a runtime error");
+        };
+        assert_eq!("Expected 1 arguments but got 0.", error.message);
+        assert!(vm.stack().is_empty());
+    }
+
+    #[test]
+    fn sleep_rejects_bad_durations() {
+        let mut vm = Vm::new();
+        assert!(vm.interpret_source("sleep(0);").is_ok());
+        assert!(vm.interpret_source("sleep(1 / 0);").is_err());
+        assert!(vm.interpret_source("sleep(0 / 0);").is_err());
+        assert!(vm.interpret_source("sleep(-1);").is_err());
+    }
+
+    #[test]
+    fn roots_keep_values_alive() {
+        let mut vm = Vm::new();
+        vm.interpret_source("var s = \"hello\" + \" world\";")
+            .unwrap();
+        let s = vm.get_global("s").unwrap();
+        let script = Compiler::compile(&mut vm, "var t = s;").unwrap();
+
+        vm.reset();
+        for i in 0..10 {
+            vm.new_string(&format!("filler{i}"));
+        }
+        vm.collect_garbage();
+
+        assert_eq!(Some("hello world"), s.as_str());
+        assert_eq!(Some("t"), script.chunk.constants[0].as_str());
+
+        // Until the last clone of the root has gone
+        let count = vm.interned_string_count();
+        let clone = s.clone();
+        drop(s);
+        vm.collect_garbage();
+        assert_eq!(count, vm.interned_string_count());
+        drop(clone);
+        vm.collect_garbage();
+        assert_eq!(count - 1, vm.interned_string_count());
+
+        // Even once the VM has gone
+        drop(vm);
+        assert_eq!(Some("t"), script.chunk.constants[0].as_str());
+    }
+
+    #[test]
+    fn natives_and_globals_take_roots() {
+        let mut vm = Vm::new();
+        let kept = std::rc::Rc::new(RefCell::new(Vec::new()));
+        let stash = std::rc::Rc::clone(&kept);
+        vm.define_native("keep", 1, move |_vm, args| {
+            stash.borrow_mut().push(args[0].clone());
+            Ok(Root::from(()))
+        });
+        vm.interpret_source("keep(\"kept\" + \" arg\");").unwrap();
+
+        let greeting = vm.new_string("hi");
+        vm.set_global("greeting", &greeting);
+        drop(greeting);
+
+        vm.collect_garbage();
+        assert_eq!(Some("kept arg"), kept.borrow()[0].as_str());
+        assert_eq!(Some("hi"), vm.get_global("greeting").unwrap().as_str());
+    }
+
+    #[test]
+    fn reset_forgets_globals() {
+        let mut vm = Vm::new();
+        vm.interpret_source("var x = 1;").unwrap();
+        assert!(vm.globals().any(|(name, _)| name == "x"));
+
+        vm.reset();
+        assert!(vm.get_global("x").is_none());
+        assert!(vm.get_global("clock").is_some());
+        assert!(vm.stack().is_empty());
+    }
+
+    #[test]
+    fn runtime_errors_have_stack_traces() {
+        let mut vm = Vm::new();
+        let source = "fun f() {\n  return -nil;\n}\nf();";
+        let Err(VmError::RuntimeError(error)) = vm.interpret_source(source) else {
+            panic!("expected a runtime error");
+        };
+
+        assert_eq!("Operand must be a number.", error.message);
+        assert_eq!(2, error.line);
+        let frames: Vec<_> = error
+            .stack_trace
+            .iter()
+            .map(|frame| (frame.function.as_str(), frame.line))
+            .collect();
+        assert_eq!(vec![("f()", 2), ("script", 4)], frames);
+        assert!(error
+            .to_string()
+            .ends_with("[line 2] in f()\n[line 4] in script"));
+    }
+}